@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::prelude::*;
+use iron::{headers, status, BeforeMiddleware, Handler, IronResult, Request, Response};
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use params::{FromValue, Params};
+
+use config::AccessCodeConfig;
+use errors::*;
+use server::ApiError;
+
+const SESSION_COOKIE: &str = "wifi_connect_session";
+const SESSION_LIFETIME_SECS: u64 = 3600;
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const LOCKOUT_SECS: u64 = 60;
+const GENERATED_CODE_DIGITS: usize = 6;
+
+/// Resolves the portal access code, generating a new one on every start if
+/// a fixed code is not configured. A generated code is printed to stderr
+/// only, as logs end up in the log file, journald and the `/logs` resource.
+pub fn init_access_code(config: &Option<AccessCodeConfig>) -> Result<Option<String>> {
+    let config = match *config {
+        Some(ref config) => config,
+        None => return Ok(None),
+    };
+
+    let code = match config.code {
+        Some(ref code) => code.clone(),
+        None => {
+            let code = generate_access_code()?;
+            eprintln!("Portal access code: {}", code);
+            info!("Portal access code generated");
+            code
+        }
+    };
+
+    if let Some(ref path) = config.file {
+        write_access_code(path, &code)
+            .chain_err(|| ErrorKind::WriteAccessCode(path.display().to_string()))?;
+
+        info!("Portal access code written to {}", path.display());
+    }
+
+    Ok(Some(code))
+}
+
+/// Bytes of 250 and above are discarded, as taking them modulo 10 would make
+/// the lower digits more likely
+fn generate_access_code() -> Result<String> {
+    let mut code = String::with_capacity(GENERATED_CODE_DIGITS);
+    let mut bytes = [0u8; GENERATED_CODE_DIGITS];
+
+    while code.len() < GENERATED_CODE_DIGITS {
+        rand_bytes(&mut bytes)?;
+
+        for b in bytes.iter().filter(|&&b| b < 250) {
+            if code.len() < GENERATED_CODE_DIGITS {
+                code.push(char::from(b'0' + b % 10));
+            }
+        }
+    }
+
+    Ok(code)
+}
+
+/// The file is readable by its owner only
+fn write_access_code(path: &::std::path::Path, code: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    // The mode applies to newly created files only
+    file.set_permissions(Permissions::from_mode(0o600))?;
+
+    writeln!(file, "{}", code)?;
+    Ok(())
+}
+
+fn generate_session_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+struct FailedAttempts {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Portal access code verification with cookie based sessions. Sessions are
+/// kept with the instant they expire.
+pub struct AccessControl {
+    code: String,
+    secure_cookie: bool,
+    sessions: Mutex<HashMap<String, Instant>>,
+    failed_attempts: Mutex<HashMap<IpAddr, FailedAttempts>>,
+}
+
+impl AccessControl {
+    pub fn new(code: String, secure_cookie: bool) -> Self {
+        AccessControl {
+            code,
+            secure_cookie,
+            sessions: Mutex::new(HashMap::new()),
+            failed_attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn has_session(&self, req: &Request) -> bool {
+        match get_session_cookie(req) {
+            Some(token) => self.is_valid_session(&token),
+            None => false,
+        }
+    }
+
+    fn is_valid_session(&self, token: &str) -> bool {
+        let now = Instant::now();
+
+        let mut sessions = self.sessions.lock().unwrap();
+
+        sessions.retain(|_, expiry| now < *expiry);
+
+        sessions.contains_key(token)
+    }
+
+    fn is_locked_out(&self, client: &IpAddr) -> bool {
+        let mut failed_attempts = self.failed_attempts.lock().unwrap();
+
+        match failed_attempts.get(client).and_then(|a| a.locked_until) {
+            Some(locked_until) if Instant::now() < locked_until => true,
+            Some(_) => {
+                failed_attempts.remove(client);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn verify(&self, client: &IpAddr, code: &str) -> bool {
+        let valid =
+            code.len() == self.code.len() && memcmp::eq(code.as_bytes(), self.code.as_bytes());
+
+        let mut failed_attempts = self.failed_attempts.lock().unwrap();
+
+        if valid {
+            failed_attempts.remove(client);
+            return true;
+        }
+
+        let attempts = failed_attempts.entry(*client).or_insert(FailedAttempts {
+            count: 0,
+            locked_until: None,
+        });
+
+        attempts.count += 1;
+
+        if attempts.count >= MAX_FAILED_ATTEMPTS {
+            warn!(
+                "Too many wrong access code attempts from {} - locked for {}s",
+                client, LOCKOUT_SECS
+            );
+            attempts.count = 0;
+            attempts.locked_until = Some(Instant::now() + Duration::from_secs(LOCKOUT_SECS));
+        }
+
+        false
+    }
+
+    fn create_session(&self) -> Result<String> {
+        let token = generate_session_token()?;
+
+        self.sessions.lock().unwrap().insert(
+            token.clone(),
+            Instant::now() + Duration::from_secs(SESSION_LIFETIME_SECS),
+        );
+
+        Ok(token)
+    }
+
+    fn session_cookie(&self, token: &str) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+            SESSION_COOKIE, token, SESSION_LIFETIME_SECS
+        );
+
+        if self.secure_cookie {
+            cookie.push_str("; Secure");
+        }
+
+        cookie
+    }
+}
+
+fn get_session_cookie(req: &Request) -> Option<String> {
+    let cookies = req.headers.get::<headers::Cookie>()?;
+
+    for header in cookies.iter() {
+        for cookie in header.split(';') {
+            let mut parts = cookie.trim().splitn(2, '=');
+            if parts.next() == Some(SESSION_COOKIE) {
+                return parts.next().map(String::from);
+            }
+        }
+    }
+
+    None
+}
+
+/// Rejects requests without a valid session. It is linked only to the routes
+/// that need protection, so that captive portal detection and the static UI
+/// are always reachable.
+pub struct RequireAccess {
+    access: Arc<AccessControl>,
+}
+
+impl BeforeMiddleware for RequireAccess {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if self.access.has_session(req) {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                status::Unauthorized,
+                "access_code_required",
+                "Portal access code required",
+            )
+            .into())
+        }
+    }
+}
+
+/// Wraps a route handler with access code verification if enabled
+pub fn require_access<H: Handler>(handler: H, access: &Option<Arc<AccessControl>>) -> Chain {
+    let mut chain = Chain::new(handler);
+
+    if let Some(ref access) = *access {
+        chain.link_before(RequireAccess {
+            access: access.clone(),
+        });
+    }
+
+    chain
+}
+
+/// Verifies the `code` request parameter and starts a session on success
+pub struct Login {
+    pub access: Arc<AccessControl>,
+}
+
+/// Body of `POST /api/v1/login`, either as JSON or form encoded
+#[derive(Serialize, JsonSchema)]
+pub struct LoginRequest {
+    code: String,
+}
+
+impl Handler for Login {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let client = req.remote_addr.ip();
+
+        if self.access.is_locked_out(&client) {
+            return Err(ApiError::new(
+                status::TooManyRequests,
+                "too_many_attempts",
+                "Too many wrong access code attempts",
+            )
+            .into());
+        }
+
+        let code = match req.get_ref::<Params>() {
+            Ok(params) => params.get("code").and_then(String::from_value),
+            Err(_) => None,
+        };
+
+        let code = match code {
+            Some(code) => code,
+            None => {
+                return Err(ApiError::new(
+                    status::BadRequest,
+                    "missing_parameter",
+                    "'code' not found in request params",
+                )
+                .into())
+            }
+        };
+
+        if !self.access.verify(&client, &code) {
+            return Err(ApiError::new(
+                status::Unauthorized,
+                "wrong_access_code",
+                "Wrong access code",
+            )
+            .into());
+        }
+
+        let token = match self.access.create_session() {
+            Ok(token) => token,
+            Err(e) => {
+                let description = format!("Creating portal session failed: {}", e);
+                error!("{}", description);
+                return Err(ApiError::new(
+                    status::InternalServerError,
+                    "internal_error",
+                    &description,
+                )
+                .into());
+            }
+        };
+
+        debug!("Portal session started for {}", client);
+
+        let mut response = Response::with(status::Ok);
+        response
+            .headers
+            .set(headers::SetCookie(vec![self.access.session_cookie(&token)]));
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 42, last])
+    }
+
+    fn access() -> AccessControl {
+        AccessControl::new("123456".into(), false)
+    }
+
+    #[test]
+    fn generated_code_has_digits_only() {
+        let code = generate_access_code().unwrap();
+
+        assert_eq!(code.len(), GENERATED_CODE_DIGITS);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn verify_code() {
+        let access = access();
+
+        assert!(access.verify(&client(2), "123456"));
+        assert!(!access.verify(&client(2), "654321"));
+        assert!(!access.verify(&client(2), "12345"));
+        assert!(!access.verify(&client(2), ""));
+    }
+
+    #[test]
+    fn lockout_after_too_many_wrong_codes() {
+        let access = access();
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            assert!(!access.verify(&client(2), "000000"));
+            assert!(!access.is_locked_out(&client(2)));
+        }
+
+        assert!(!access.verify(&client(2), "000000"));
+        assert!(access.is_locked_out(&client(2)));
+        assert!(!access.is_locked_out(&client(3)));
+    }
+
+    #[test]
+    fn correct_code_resets_failed_attempts() {
+        let access = access();
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            assert!(!access.verify(&client(2), "000000"));
+        }
+
+        assert!(access.verify(&client(2), "123456"));
+        assert!(!access.verify(&client(2), "000000"));
+        assert!(!access.is_locked_out(&client(2)));
+    }
+
+    #[test]
+    fn lockout_ends() {
+        let access = access();
+
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            access.verify(&client(2), "000000");
+        }
+
+        for attempts in access.failed_attempts.lock().unwrap().values_mut() {
+            attempts.locked_until = Some(Instant::now());
+        }
+
+        assert!(!access.is_locked_out(&client(2)));
+        assert!(access.failed_attempts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn session_expires() {
+        let access = access();
+        let token = access.create_session().unwrap();
+
+        assert!(access.is_valid_session(&token));
+        assert!(!access.is_valid_session("unknown"));
+
+        for expiry in access.sessions.lock().unwrap().values_mut() {
+            *expiry = Instant::now();
+        }
+
+        assert!(!access.is_valid_session(&token));
+        assert!(access.sessions.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn secure_session_cookie() {
+        assert!(!access().session_cookie("abc").contains("Secure"));
+        assert!(AccessControl::new("123456".into(), true)
+            .session_cookie("abc")
+            .ends_with("; Secure"));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use network_manager::AccessPoint;
+
+use config::{Config, PortalBand, PortalChannel};
+use nm_dbus::access_point_radios;
+
+/// Channels not overlapping each other
+const CHANNELS_2_4GHZ: &[u32] = &[1, 6, 11];
+
+/// Channels without DFS, which would delay the hotspot by at least a minute
+/// of listening for radar
+const CHANNELS_5GHZ: &[u32] = &[36, 40, 44, 48, 149, 153, 157, 161, 165];
+
+/// 2.4GHz channels closer than this overlap
+const CHANNEL_OVERLAP_2_4GHZ: u32 = 5;
+
+/// The band and channel of the portal hotspot, if set. Automatic channels are
+/// chosen based on the scan results, where strong access points count more.
+/// `access_points` should hold every access point found, including hidden
+/// ones and several of the same network, which are told apart by BSSID.
+pub fn portal_channel(
+    config: &Config,
+    supports_5ghz: bool,
+    access_points: &[AccessPoint],
+) -> Option<(PortalBand, Option<u32>)> {
+    let band = config.portal_band?;
+
+    match config.portal_channel {
+        Some(PortalChannel::Auto) => {}
+        Some(PortalChannel::Channel(channel)) => return Some((band, Some(channel))),
+        None => return Some((band, None)),
+    }
+
+    let mut candidates = Vec::new();
+
+    if band != PortalBand::Band5Ghz {
+        candidates.extend(CHANNELS_2_4GHZ.iter().map(|c| (PortalBand::Band2_4Ghz, *c)));
+    }
+
+    if band == PortalBand::Band5Ghz || (band == PortalBand::Auto && supports_5ghz) {
+        candidates.extend(CHANNELS_5GHZ.iter().map(|c| (PortalBand::Band5Ghz, *c)));
+    }
+
+    let paths = access_points
+        .iter()
+        .map(|access_point| access_point.path.as_str())
+        .collect::<Vec<_>>();
+
+    let mut bssids = HashSet::new();
+
+    let occupied = access_points
+        .iter()
+        .zip(access_point_radios(&paths))
+        .filter_map(|(access_point, radio)| radio.map(|radio| (radio, access_point.strength)))
+        .filter(|(radio, _)| bssids.insert(radio.bssid.clone()))
+        .filter_map(|(radio, strength)| {
+            frequency_to_channel(radio.frequency).map(|channel| (channel, strength))
+        })
+        .collect::<Vec<_>>();
+
+    let (band, channel) = least_congested(candidates, &occupied)?;
+
+    info!("Least congested portal channel: {}", channel);
+
+    Some((band, Some(channel)))
+}
+
+/// `occupied` holds the channel and signal strength of each access point.
+/// The first of equally congested candidates wins.
+fn least_congested(
+    candidates: Vec<(PortalBand, u32)>,
+    occupied: &[(u32, u32)],
+) -> Option<(PortalBand, u32)> {
+    candidates
+        .into_iter()
+        .min_by_key(|&(_, channel)| congestion(channel, occupied))
+}
+
+fn congestion(channel: u32, occupied: &[(u32, u32)]) -> u32 {
+    occupied
+        .iter()
+        .filter(|&&(other, _)| overlaps(channel, other))
+        .map(|&(_, strength)| 1 + strength)
+        .sum()
+}
+
+fn overlaps(channel: u32, other: u32) -> bool {
+    if channel <= 14 && other <= 14 {
+        channel.max(other) - channel.min(other) < CHANNEL_OVERLAP_2_4GHZ
+    } else {
+        channel == other
+    }
+}
+
+fn frequency_to_channel(frequency: u32) -> Option<u32> {
+    match frequency {
+        2484 => Some(14),
+        2412..=2472 => Some((frequency - 2407) / 5),
+        5160..=5885 => Some((frequency - 5000) / 5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates_2_4ghz() -> Vec<(PortalBand, u32)> {
+        CHANNELS_2_4GHZ
+            .iter()
+            .map(|c| (PortalBand::Band2_4Ghz, *c))
+            .collect()
+    }
+
+    #[test]
+    fn frequency_to_channel_maps_both_bands() {
+        assert_eq!(frequency_to_channel(2412), Some(1));
+        assert_eq!(frequency_to_channel(2437), Some(6));
+        assert_eq!(frequency_to_channel(2472), Some(13));
+        assert_eq!(frequency_to_channel(2484), Some(14));
+        assert_eq!(frequency_to_channel(5180), Some(36));
+        assert_eq!(frequency_to_channel(5825), Some(165));
+    }
+
+    #[test]
+    fn frequency_to_channel_rejects_unknown_frequencies() {
+        assert_eq!(frequency_to_channel(0), None);
+        assert_eq!(frequency_to_channel(2400), None);
+        assert_eq!(frequency_to_channel(60480), None);
+    }
+
+    #[test]
+    fn overlapping_2_4ghz_channels() {
+        assert!(overlaps(1, 1));
+        assert!(overlaps(1, 4));
+        assert!(overlaps(6, 2));
+        assert!(!overlaps(1, 6));
+        assert!(!overlaps(11, 6));
+    }
+
+    #[test]
+    fn overlapping_5ghz_channels() {
+        assert!(overlaps(36, 36));
+        assert!(!overlaps(36, 40));
+        assert!(!overlaps(1, 36));
+    }
+
+    #[test]
+    fn congestion_counts_strength_of_overlapping_access_points() {
+        let occupied = [(1, 80), (3, 20), (11, 50)];
+
+        assert_eq!(congestion(1, &occupied), 81 + 21);
+        assert_eq!(congestion(6, &occupied), 21);
+        assert_eq!(congestion(11, &occupied), 51);
+        assert_eq!(congestion(36, &occupied), 0);
+    }
+
+    #[test]
+    fn least_congested_picks_the_quietest_channel() {
+        let occupied = [(1, 80), (6, 30), (6, 30), (11, 40)];
+
+        assert_eq!(
+            least_congested(candidates_2_4ghz(), &occupied),
+            Some((PortalBand::Band2_4Ghz, 11))
+        );
+    }
+
+    #[test]
+    fn least_congested_prefers_the_first_candidate_on_ties() {
+        assert_eq!(
+            least_congested(candidates_2_4ghz(), &[]),
+            Some((PortalBand::Band2_4Ghz, 1))
+        );
+    }
+
+    #[test]
+    fn least_congested_moves_to_5ghz_when_2_4ghz_is_busy() {
+        let mut candidates = candidates_2_4ghz();
+        candidates.push((PortalBand::Band5Ghz, 36));
+
+        let occupied = [(1, 10), (6, 10), (11, 10)];
+
+        assert_eq!(
+            least_congested(candidates, &occupied),
+            Some((PortalBand::Band5Ghz, 36))
+        );
+    }
+
+    #[test]
+    fn least_congested_without_candidates() {
+        assert_eq!(least_congested(Vec::new(), &[]), None);
+    }
+}
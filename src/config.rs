@@ -1,10 +1,15 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use errors::*;
 
 const DEFAULT_GATEWAY: &str = "192.168.42.1";
 const DEFAULT_DHCP_RANGE: &str = "192.168.42.2,192.168.42.254";
@@ -12,20 +17,162 @@ const DEFAULT_SSID: &str = "WiFi Connect";
 const DEFAULT_ACTIVITY_TIMEOUT: &str = "0";
 const DEFAULT_UI_DIRECTORY: &str = "ui";
 const DEFAULT_LISTENING_PORT: &str = "80";
+const DEFAULT_TLS_LISTENING_PORT: &str = "443";
+const DEFAULT_TLS_DIRECTORY: &str = "/var/lib/wifi-connect/tls";
+const DEFAULT_CONTROL_SOCKET: &str = "/run/wifi-connect/control.sock";
+const DEFAULT_LOG_FORMAT: &str = "text";
+const DEFAULT_LOG_FILE_MAX_SIZE: &str = "10485760";
+const DEFAULT_LOG_FILE_COUNT: &str = "3";
+const DEFAULT_LOG_BUFFER_SIZE: &str = "500";
+const DEFAULT_LOG_FILTER: &str = "info,iron::iron=off";
+const DEFAULT_INIT_SYSTEM: &str = "auto";
+const DEFAULT_NETWORK_MANAGER_SERVICE: &str = "NetworkManager";
+const DEFAULT_NETWORK_MANAGER_TIMEOUT: &str = "30";
+const DEFAULT_DEVICE_TIMEOUT: &str = "0";
+
+/// Configuration shared with the web server, so that reloading it applies to
+/// the running server
+pub type SharedConfig = Arc<RwLock<Config>>;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Config {
     pub interface: Option<String>,
+    pub target_interface: Option<String>,
     pub ssid: String,
     pub passphrase: Option<String>,
+    pub portal_band: Option<PortalBand>,
+    pub portal_channel: Option<PortalChannel>,
+    pub country: Option<String>,
     pub gateway: Ipv4Addr,
     pub dhcp_range: String,
     pub listening_port: u16,
     pub activity_timeout: u64,
     pub ui_directory: PathBuf,
+    pub tls: Option<TlsConfig>,
+    pub access_code: Option<AccessCodeConfig>,
+    pub control_socket: PathBuf,
+    pub daemon: bool,
+    pub capabilities: bool,
+    pub socket_activation: bool,
+    pub init_system: InitSystem,
+    pub network_manager_service: String,
+    pub network_manager_timeout: u64,
+    pub device_timeout: u64,
+    pub unblock_radio: bool,
+    pub manage_device: bool,
+    pub dbus: Option<DbusBus>,
+    pub hooks: HooksConfig,
+    pub metrics_port: Option<u16>,
+    pub portal_metrics: bool,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Journald,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    pub file: Option<PathBuf>,
+    pub file_max_size: u64,
+    pub file_count: u32,
+    pub buffer_size: usize,
+    pub filter: String,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct HooksConfig {
+    pub script: Option<PathBuf>,
+    pub url: Option<String>,
+}
+
+/// Init system used for starting NetworkManager. With `None` WiFi Connect
+/// only waits for NetworkManager to appear on D-Bus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InitSystem {
+    Auto,
+    Systemd,
+    OpenRc,
+    Runit,
+    S6,
+    None,
+}
+
+/// `Auto` picks the least congested channel of both bands, if the device
+/// supports 5GHz
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortalBand {
+    Band2_4Ghz,
+    Band5Ghz,
+    Auto,
+}
+
+/// `Auto` picks the least congested channel based on the scan results
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortalChannel {
+    Auto,
+    Channel(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DbusBus {
+    System,
+    Session,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TlsConfig {
+    pub certificate: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub directory: PathBuf,
+    pub listening_port: u16,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AccessCodeConfig {
+    pub code: Option<String>,
+    pub file: Option<PathBuf>,
 }
 
-pub fn get_config() -> Config {
+/// Effective configuration as exposed by the API, without secrets
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct PortalConfig {
+    pub interface: Option<String>,
+    pub target_interface: Option<String>,
+    pub ssid: String,
+    pub passphrase_protected: bool,
+    pub gateway: String,
+    pub dhcp_range: String,
+    pub listening_port: u16,
+    pub activity_timeout: u64,
+    pub tls: bool,
+    pub access_code_required: bool,
+    pub daemon: bool,
+}
+
+impl<'a> From<&'a Config> for PortalConfig {
+    fn from(config: &'a Config) -> Self {
+        PortalConfig {
+            interface: config.interface.clone(),
+            target_interface: config.target_interface.clone(),
+            ssid: config.ssid.clone(),
+            passphrase_protected: config.passphrase.is_some(),
+            gateway: config.gateway.to_string(),
+            dhcp_range: config.dhcp_range.clone(),
+            listening_port: config.listening_port,
+            activity_timeout: config.activity_timeout,
+            tls: config.tls.is_some(),
+            access_code_required: config.access_code.is_some(),
+            daemon: config.daemon,
+        }
+    }
+}
+
+pub fn get_config() -> Result<Config> {
     let matches = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
@@ -35,7 +182,36 @@ pub fn get_config() -> Config {
                 .short("i")
                 .long("portal-interface")
                 .value_name("interface")
-                .help("Wireless network interface to be used by WiFi Connect")
+                .help("Wireless network interface to be used by WiFi Connect, or mac=<address> or driver=<name>")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target-interface")
+                .long("target-interface")
+                .value_name("interface")
+                .help("Wireless network interface connecting to the chosen network, so that the portal interface keeps hosting the portal")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-band")
+                .long("portal-band")
+                .value_name("band")
+                .possible_values(&["2.4", "5", "auto"])
+                .help("WiFi band of the captive portal (default: chosen by NetworkManager)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-channel")
+                .long("portal-channel")
+                .value_name("channel")
+                .help("WiFi channel of the captive portal, or auto for the least congested one (default: chosen by NetworkManager)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("country")
+                .long("country")
+                .value_name("country")
+                .help("ISO 3166-1 alpha-2 country code of the WiFi regulatory domain, e.g. DE (default: as set by the system)")
                 .takes_value(true),
         )
         .arg(
@@ -109,31 +285,285 @@ pub fn get_config() -> Config {
                 ))
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("portal-tls")
+                .long("portal-tls")
+                .help("Serve the captive portal over HTTPS"),
+        )
+        .arg(
+            Arg::with_name("portal-tls-certificate")
+                .long("portal-tls-certificate")
+                .value_name("certificate")
+                .help("PEM certificate of the captive portal HTTPS server (default: self-signed)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-tls-key")
+                .long("portal-tls-key")
+                .value_name("key")
+                .help("PEM private key of the captive portal HTTPS server (default: self-signed)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-tls-directory")
+                .long("portal-tls-directory")
+                .value_name("tls_directory")
+                .help(&format!(
+                    "Directory for the generated self-signed certificate (default: {})",
+                    DEFAULT_TLS_DIRECTORY
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-tls-listening-port")
+                .long("portal-tls-listening-port")
+                .value_name("tls_listening_port")
+                .help(&format!(
+                    "Listening port of the captive portal HTTPS server (default: {})",
+                    DEFAULT_TLS_LISTENING_PORT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-access-code")
+                .long("portal-access-code")
+                .value_name("access_code")
+                .help("Access code required for configuring the device from the captive portal")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-access-code-generate")
+                .long("portal-access-code-generate")
+                .help("Require a random access code generated on every start"),
+        )
+        .arg(
+            Arg::with_name("portal-access-code-file")
+                .long("portal-access-code-file")
+                .value_name("access_code_file")
+                .help("File to write the portal access code to (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .long("control-socket")
+                .value_name("control_socket")
+                .help(&format!(
+                    "Unix socket of the control API (default: {})",
+                    DEFAULT_CONTROL_SOCKET
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dbus-service")
+                .long("dbus-service")
+                .help("Export the io.balena.WifiConnect D-Bus service"),
+        )
+        .arg(
+            Arg::with_name("dbus-bus")
+                .long("dbus-bus")
+                .value_name("bus")
+                .possible_values(&["system", "session"])
+                .help("Bus of the D-Bus service (default: system)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hook-script")
+                .long("hook-script")
+                .value_name("script")
+                .help("Script to run on events (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hook-url")
+                .long("hook-url")
+                .value_name("url")
+                .help("Local URL to POST events to as JSON (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .value_name("port")
+                .help("Serve metrics on this port of the loopback interface (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-metrics")
+                .long("portal-metrics")
+                .help("Serve metrics from the captive portal web server"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("format")
+                .possible_values(&["text", "json", "journald"])
+                .help(&format!(
+                    "Log output format (default: {})",
+                    DEFAULT_LOG_FORMAT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("log_file")
+                .help("Write logs to a file instead of stderr (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file-max-size")
+                .long("log-file-max-size")
+                .value_name("bytes")
+                .help(&format!(
+                    "Size at which the log file is rotated (default: {})",
+                    DEFAULT_LOG_FILE_MAX_SIZE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-file-count")
+                .long("log-file-count")
+                .value_name("count")
+                .help(&format!(
+                    "Number of rotated log files to keep (default: {})",
+                    DEFAULT_LOG_FILE_COUNT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-buffer-size")
+                .long("log-buffer-size")
+                .value_name("records")
+                .help(&format!(
+                    "Number of recent log records shown in the portal (default: {})",
+                    DEFAULT_LOG_BUFFER_SIZE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config-file")
+                .long("config-file")
+                .value_name("config_file")
+                .help("File with KEY=VALUE lines using the environment variable names, re-read on SIGHUP (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capabilities")
+                .long("capabilities")
+                .help("Run without root privileges if granted the CAP_NET_ADMIN, CAP_NET_BIND_SERVICE and CAP_NET_RAW capabilities"),
+        )
+        .arg(
+            Arg::with_name("socket-activation")
+                .long("socket-activation")
+                .help("Serve plain HTTP on the socket passed by systemd"),
+        )
+        .arg(
+            Arg::with_name("init-system")
+                .long("init-system")
+                .value_name("init_system")
+                .possible_values(&["auto", "systemd", "openrc", "runit", "s6", "none"])
+                .help(&format!(
+                    "Init system starting NetworkManager (default: {})",
+                    DEFAULT_INIT_SYSTEM
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network-manager-service")
+                .long("network-manager-service")
+                .value_name("service")
+                .help(&format!(
+                    "NetworkManager service name for OpenRC, runit and s6 (default: {})",
+                    DEFAULT_NETWORK_MANAGER_SERVICE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network-manager-timeout")
+                .long("network-manager-timeout")
+                .value_name("timeout")
+                .help(&format!(
+                    "Seconds to wait for NetworkManager to appear on D-Bus (default: {})",
+                    DEFAULT_NETWORK_MANAGER_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("device-timeout")
+                .long("device-timeout")
+                .value_name("timeout")
+                .help(&format!(
+                    "Seconds to wait for a WiFi device to appear (default: {})",
+                    DEFAULT_DEVICE_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manage-device")
+                .long("manage-device")
+                .help("Make the WiFi device managed by NetworkManager if it is unmanaged"),
+        )
+        .arg(
+            Arg::with_name("unblock-radio")
+                .long("unblock-radio")
+                .help("Lift rfkill soft blocks and enable WiFi in NetworkManager if disabled"),
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Keep running and start the portal only on request over the control socket"),
+        )
         .get_matches();
 
+    let config_file: Option<PathBuf> = matches.value_of("config-file").map_or_else(
+        || env::var("CONFIG_FILE").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    let vars = Vars {
+        file: match config_file {
+            Some(ref path) => read_config_file(path)?,
+            None => BTreeMap::new(),
+        },
+    };
+
     let interface: Option<String> = matches.value_of("portal-interface").map_or_else(
-        || env::var("PORTAL_INTERFACE").ok(),
+        || vars.var("PORTAL_INTERFACE").ok(),
+        |v| Some(v.to_string()),
+    );
+
+    let target_interface: Option<String> = matches.value_of("target-interface").map_or_else(
+        || vars.var("TARGET_INTERFACE").ok(),
         |v| Some(v.to_string()),
     );
 
     let ssid: String = matches.value_of("portal-ssid").map_or_else(
-        || env::var("PORTAL_SSID").unwrap_or_else(|_| DEFAULT_SSID.to_string()),
+        || {
+            vars.var("PORTAL_SSID")
+                .unwrap_or_else(|_| DEFAULT_SSID.to_string())
+        },
         String::from,
     );
 
     let passphrase: Option<String> = matches.value_of("portal-passphrase").map_or_else(
-        || env::var("PORTAL_PASSPHRASE").ok(),
+        || vars.var("PORTAL_PASSPHRASE").ok(),
         |v| Some(v.to_string()),
     );
 
     let gateway = Ipv4Addr::from_str(&matches.value_of("portal-gateway").map_or_else(
-        || env::var("PORTAL_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
+        || {
+            vars.var("PORTAL_GATEWAY")
+                .unwrap_or_else(|_| DEFAULT_GATEWAY.to_string())
+        },
         String::from,
     ))
-    .expect("Cannot parse gateway address");
+    .chain_err(|| invalid_config("Cannot parse gateway address"))?;
 
     let dhcp_range = matches.value_of("portal-dhcp-range").map_or_else(
-        || env::var("PORTAL_DHCP_RANGE").unwrap_or_else(|_| DEFAULT_DHCP_RANGE.to_string()),
+        || {
+            vars.var("PORTAL_DHCP_RANGE")
+                .unwrap_or_else(|_| DEFAULT_DHCP_RANGE.to_string())
+        },
         String::from,
     );
 
@@ -141,40 +571,489 @@ pub fn get_config() -> Config {
         .value_of("portal-listening-port")
         .map_or_else(
             || {
-                env::var("PORTAL_LISTENING_PORT")
+                vars.var("PORTAL_LISTENING_PORT")
                     .unwrap_or_else(|_| DEFAULT_LISTENING_PORT.to_string())
             },
             String::from,
         )
         .parse::<u16>()
-        .expect("Cannot parse listening port number");
+        .chain_err(|| invalid_config("Cannot parse listening port number"))?;
 
     let activity_timeout = u64::from_str(&matches.value_of("activity-timeout").map_or_else(
-        || env::var("ACTIVITY_TIMEOUT").unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string()),
+        || {
+            vars.var("ACTIVITY_TIMEOUT")
+                .unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string())
+        },
         String::from,
     ))
-    .expect("Cannot parse activity timeout");
+    .chain_err(|| invalid_config("Cannot parse activity timeout"))?;
+
+    let ui_directory = get_ui_directory(matches.value_of("ui-directory"), &vars);
+
+    let tls = if get_flag(&matches, &vars, "portal-tls", "PORTAL_TLS") {
+        Some(get_tls_config(&matches, &vars)?)
+    } else {
+        None
+    };
+
+    let access_code = get_access_code_config(&matches, &vars);
+
+    let control_socket = PathBuf::from(matches.value_of("control-socket").map_or_else(
+        || {
+            vars.var("CONTROL_SOCKET")
+                .unwrap_or_else(|_| DEFAULT_CONTROL_SOCKET.to_string())
+        },
+        String::from,
+    ));
+
+    let daemon = get_flag(&matches, &vars, "daemon", "DAEMON");
+
+    let capabilities = get_flag(&matches, &vars, "capabilities", "CAPABILITIES");
 
-    let ui_directory = get_ui_directory(matches.value_of("ui-directory"));
+    let socket_activation = get_flag(&matches, &vars, "socket-activation", "SOCKET_ACTIVATION");
 
-    Config {
+    let init_system = get_init_system(&matches, &vars)?;
+
+    let (portal_band, portal_channel) = get_portal_band_and_channel(&matches, &vars)?;
+
+    let country = matches
+        .value_of("country")
+        .map_or_else(|| vars.var("COUNTRY").ok(), |v| Some(v.to_string()))
+        .map(|country| -> Result<String> {
+            let country = country.to_uppercase();
+
+            // `00` is the world domain
+            let valid = country == "00"
+                || (country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()));
+
+            if !valid {
+                bail!(invalid_config(&format!(
+                    "Cannot parse country code: {}",
+                    country
+                )));
+            }
+
+            Ok(country)
+        })
+        .transpose()?;
+
+    let network_manager_service = matches.value_of("network-manager-service").map_or_else(
+        || {
+            vars.var("NETWORK_MANAGER_SERVICE")
+                .unwrap_or_else(|_| DEFAULT_NETWORK_MANAGER_SERVICE.to_string())
+        },
+        String::from,
+    );
+
+    let network_manager_timeout =
+        u64::from_str(&matches.value_of("network-manager-timeout").map_or_else(
+            || {
+                vars.var("NETWORK_MANAGER_TIMEOUT")
+                    .unwrap_or_else(|_| DEFAULT_NETWORK_MANAGER_TIMEOUT.to_string())
+            },
+            String::from,
+        ))
+        .chain_err(|| invalid_config("Cannot parse NetworkManager timeout"))?;
+
+    let device_timeout = u64::from_str(&matches.value_of("device-timeout").map_or_else(
+        || {
+            vars.var("DEVICE_TIMEOUT")
+                .unwrap_or_else(|_| DEFAULT_DEVICE_TIMEOUT.to_string())
+        },
+        String::from,
+    ))
+    .chain_err(|| invalid_config("Cannot parse device timeout"))?;
+
+    let unblock_radio = get_flag(&matches, &vars, "unblock-radio", "UNBLOCK_RADIO");
+
+    let manage_device = get_flag(&matches, &vars, "manage-device", "MANAGE_DEVICE");
+
+    let dbus = if get_flag(&matches, &vars, "dbus-service", "DBUS_SERVICE") {
+        Some(get_dbus_bus(&matches, &vars)?)
+    } else {
+        None
+    };
+
+    let hooks = get_hooks_config(&matches, &vars);
+
+    let metrics_port: Option<u16> = matches
+        .value_of("metrics-port")
+        .map_or_else(|| vars.var("METRICS_PORT").ok(), |v| Some(v.to_string()))
+        .map(|port| {
+            port.parse()
+                .chain_err(|| invalid_config("Cannot parse metrics port number"))
+        })
+        .transpose()?;
+
+    let portal_metrics = get_flag(&matches, &vars, "portal-metrics", "PORTAL_METRICS");
+
+    let log = get_log_config(&matches, &vars)?;
+
+    Ok(Config {
         interface,
+        target_interface,
         ssid,
         passphrase,
+        portal_band,
+        portal_channel,
+        country,
         gateway,
         dhcp_range,
         listening_port,
         activity_timeout,
         ui_directory,
+        tls,
+        access_code,
+        control_socket,
+        daemon,
+        capabilities,
+        socket_activation,
+        init_system,
+        network_manager_service,
+        network_manager_timeout,
+        device_timeout,
+        unblock_radio,
+        manage_device,
+        dbus,
+        hooks,
+        metrics_port,
+        portal_metrics,
+        log,
+    })
+}
+
+fn get_log_config(matches: &ArgMatches, vars: &Vars) -> Result<LogConfig> {
+    let format = matches.value_of("log-format").map_or_else(
+        || {
+            vars.var("LOG_FORMAT")
+                .unwrap_or_else(|_| DEFAULT_LOG_FORMAT.to_string())
+        },
+        String::from,
+    );
+
+    let format = match format.as_str() {
+        "text" => LogFormat::Text,
+        "json" => LogFormat::Json,
+        "journald" => LogFormat::Journald,
+        _ => bail!(invalid_config(&format!(
+            "Cannot parse log format: {}",
+            format
+        ))),
+    };
+
+    let file: Option<PathBuf> = matches.value_of("log-file").map_or_else(
+        || vars.var("LOG_FILE").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    let file_max_size = u64::from_str(&matches.value_of("log-file-max-size").map_or_else(
+        || {
+            vars.var("LOG_FILE_MAX_SIZE")
+                .unwrap_or_else(|_| DEFAULT_LOG_FILE_MAX_SIZE.to_string())
+        },
+        String::from,
+    ))
+    .chain_err(|| invalid_config("Cannot parse log file max size"))?;
+
+    let file_count = u32::from_str(&matches.value_of("log-file-count").map_or_else(
+        || {
+            vars.var("LOG_FILE_COUNT")
+                .unwrap_or_else(|_| DEFAULT_LOG_FILE_COUNT.to_string())
+        },
+        String::from,
+    ))
+    .chain_err(|| invalid_config("Cannot parse log file count"))?;
+
+    let buffer_size = usize::from_str(&matches.value_of("log-buffer-size").map_or_else(
+        || {
+            vars.var("LOG_BUFFER_SIZE")
+                .unwrap_or_else(|_| DEFAULT_LOG_BUFFER_SIZE.to_string())
+        },
+        String::from,
+    ))
+    .chain_err(|| invalid_config("Cannot parse log buffer size"))?;
+
+    let filter = vars
+        .var("RUST_LOG")
+        .unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string());
+
+    Ok(LogConfig {
+        format,
+        file,
+        file_max_size,
+        file_count,
+        buffer_size,
+        filter,
+    })
+}
+
+fn get_hooks_config(matches: &ArgMatches, vars: &Vars) -> HooksConfig {
+    let script: Option<PathBuf> = matches.value_of("hook-script").map_or_else(
+        || vars.var("HOOK_SCRIPT").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    let url: Option<String> = matches
+        .value_of("hook-url")
+        .map_or_else(|| vars.var("HOOK_URL").ok(), |v| Some(v.to_string()));
+
+    HooksConfig { script, url }
+}
+
+fn get_init_system(matches: &ArgMatches, vars: &Vars) -> Result<InitSystem> {
+    let init_system = matches.value_of("init-system").map_or_else(
+        || {
+            vars.var("INIT_SYSTEM")
+                .unwrap_or_else(|_| DEFAULT_INIT_SYSTEM.to_string())
+        },
+        String::from,
+    );
+
+    match init_system.as_str() {
+        "auto" => Ok(InitSystem::Auto),
+        "systemd" => Ok(InitSystem::Systemd),
+        "openrc" => Ok(InitSystem::OpenRc),
+        "runit" => Ok(InitSystem::Runit),
+        "s6" => Ok(InitSystem::S6),
+        "none" => Ok(InitSystem::None),
+        _ => bail!(invalid_config(&format!(
+            "Cannot parse init system: {}",
+            init_system
+        ))),
+    }
+}
+
+/// NetworkManager needs the band for a channel, so it is derived from the
+/// channel if not set. An automatic band implies an automatic channel.
+fn get_portal_band_and_channel(
+    matches: &ArgMatches,
+    vars: &Vars,
+) -> Result<(Option<PortalBand>, Option<PortalChannel>)> {
+    let band = matches
+        .value_of("portal-band")
+        .map_or_else(|| vars.var("PORTAL_BAND").ok(), |v| Some(v.to_string()))
+        .map(|band| -> Result<PortalBand> {
+            match band.as_str() {
+                "2.4" => Ok(PortalBand::Band2_4Ghz),
+                "5" => Ok(PortalBand::Band5Ghz),
+                "auto" => Ok(PortalBand::Auto),
+                _ => bail!(invalid_config(&format!(
+                    "Cannot parse portal band: {}",
+                    band
+                ))),
+            }
+        })
+        .transpose()?;
+
+    let channel = matches
+        .value_of("portal-channel")
+        .map_or_else(|| vars.var("PORTAL_CHANNEL").ok(), |v| Some(v.to_string()))
+        .map(|channel| match channel.as_str() {
+            "auto" => Ok(PortalChannel::Auto),
+            _ => u32::from_str(&channel)
+                .map(PortalChannel::Channel)
+                .chain_err(|| invalid_config("Cannot parse portal channel")),
+        })
+        .transpose()?;
+
+    match (band, channel) {
+        (Some(PortalBand::Auto), Some(PortalChannel::Channel(_))) => bail!(invalid_config(
+            "A portal channel cannot be set with an automatic portal band"
+        )),
+        (Some(PortalBand::Auto), None) => Ok((band, Some(PortalChannel::Auto))),
+        (Some(band), Some(PortalChannel::Channel(channel))) => {
+            if band_of_channel(channel) != Some(band) {
+                bail!(invalid_config(&format!(
+                    "Portal channel {} is not in the portal band",
+                    channel
+                )));
+            }
+
+            Ok((Some(band), Some(PortalChannel::Channel(channel))))
+        }
+        (None, Some(PortalChannel::Channel(channel))) => {
+            let band = band_of_channel(channel)
+                .ok_or_else(|| invalid_config(&format!("Invalid portal channel: {}", channel)))?;
+
+            Ok((Some(band), Some(PortalChannel::Channel(channel))))
+        }
+        (None, Some(PortalChannel::Auto)) => {
+            Ok((Some(PortalBand::Band2_4Ghz), Some(PortalChannel::Auto)))
+        }
+        _ => Ok((band, channel)),
+    }
+}
+
+fn band_of_channel(channel: u32) -> Option<PortalBand> {
+    match channel {
+        1..=14 => Some(PortalBand::Band2_4Ghz),
+        32..=177 => Some(PortalBand::Band5Ghz),
+        _ => None,
+    }
+}
+
+/// The session bus allows testing against a private bus, e.g. started with
+/// `dbus-run-session`
+fn get_dbus_bus(matches: &ArgMatches, vars: &Vars) -> Result<DbusBus> {
+    let bus = matches.value_of("dbus-bus").map_or_else(
+        || {
+            vars.var("DBUS_BUS")
+                .unwrap_or_else(|_| "system".to_string())
+        },
+        String::from,
+    );
+
+    match bus.as_str() {
+        "system" => Ok(DbusBus::System),
+        "session" => Ok(DbusBus::Session),
+        _ => bail!(invalid_config(&format!("Cannot parse D-Bus bus: {}", bus))),
+    }
+}
+
+fn get_tls_config(matches: &ArgMatches, vars: &Vars) -> Result<TlsConfig> {
+    let certificate: Option<PathBuf> = matches.value_of("portal-tls-certificate").map_or_else(
+        || vars.var("PORTAL_TLS_CERTIFICATE").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    let key: Option<PathBuf> = matches.value_of("portal-tls-key").map_or_else(
+        || vars.var("PORTAL_TLS_KEY").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    let directory = PathBuf::from(matches.value_of("portal-tls-directory").map_or_else(
+        || {
+            vars.var("PORTAL_TLS_DIRECTORY")
+                .unwrap_or_else(|_| DEFAULT_TLS_DIRECTORY.to_string())
+        },
+        String::from,
+    ));
+
+    let listening_port = matches
+        .value_of("portal-tls-listening-port")
+        .map_or_else(
+            || {
+                vars.var("PORTAL_TLS_LISTENING_PORT")
+                    .unwrap_or_else(|_| DEFAULT_TLS_LISTENING_PORT.to_string())
+            },
+            String::from,
+        )
+        .parse::<u16>()
+        .chain_err(|| invalid_config("Cannot parse TLS listening port number"))?;
+
+    Ok(TlsConfig {
+        certificate,
+        key,
+        directory,
+        listening_port,
+    })
+}
+
+fn get_access_code_config(matches: &ArgMatches, vars: &Vars) -> Option<AccessCodeConfig> {
+    let code: Option<String> = matches.value_of("portal-access-code").map_or_else(
+        || vars.var("PORTAL_ACCESS_CODE").ok(),
+        |v| Some(v.to_string()),
+    );
+
+    let generate = get_flag(
+        matches,
+        vars,
+        "portal-access-code-generate",
+        "PORTAL_ACCESS_CODE_GENERATE",
+    );
+
+    if code.is_none() && !generate {
+        return None;
+    }
+
+    let file: Option<PathBuf> = matches.value_of("portal-access-code-file").map_or_else(
+        || vars.var("PORTAL_ACCESS_CODE_FILE").ok().map(PathBuf::from),
+        |v| Some(PathBuf::from(v)),
+    );
+
+    Some(AccessCodeConfig { code, file })
+}
+
+/// Values from `--config-file`, used when neither the command line argument
+/// nor the environment variable is set
+struct Vars {
+    file: BTreeMap<String, String>,
+}
+
+impl Vars {
+    /// Environment variable, or the value of the same name from the config file
+    fn var(&self, name: &str) -> ::std::result::Result<String, env::VarError> {
+        env::var(name).or_else(|e| match self.file.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(e),
+        })
+    }
+}
+
+fn invalid_config(message: &str) -> ErrorKind {
+    ErrorKind::InvalidConfig(message.into())
+}
+
+/// Blank lines and lines starting with `#` are skipped. Values may be quoted.
+fn read_config_file(path: &Path) -> Result<BTreeMap<String, String>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| invalid_config(&format!("Cannot read config file {}", path.display())))?;
+
+    let mut vars = BTreeMap::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap().trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => bail!(invalid_config(&format!(
+                "Cannot parse config file line: {}",
+                line
+            ))),
+        };
+
+        let value = if value.len() >= 2
+            && (value.starts_with('"') && value.ends_with('"')
+                || value.starts_with('\'') && value.ends_with('\''))
+        {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+
+        vars.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(vars)
+}
+
+/// Flags are enabled either from the command line or with an environment
+/// variable set to `1`, `true` or `yes`
+fn get_flag(matches: &ArgMatches, vars: &Vars, name: &str, env_name: &str) -> bool {
+    if matches.is_present(name) {
+        return true;
+    }
+
+    match vars.var(env_name) {
+        Ok(value) => is_truthy(&value),
+        Err(_) => false,
     }
 }
 
-fn get_ui_directory(cmd_ui_directory: Option<&str>) -> PathBuf {
+fn is_truthy(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+fn get_ui_directory(cmd_ui_directory: Option<&str>, vars: &Vars) -> PathBuf {
     if let Some(ui_directory) = cmd_ui_directory {
         return PathBuf::from(ui_directory);
     }
 
-    if let Ok(ui_directory) = env::var("UI_DIRECTORY") {
+    if let Ok(ui_directory) = vars.var("UI_DIRECTORY") {
         return PathBuf::from(ui_directory);
     }
 
@@ -216,3 +1095,172 @@ fn get_install_ui_directory() -> Option<PathBuf> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    /// Variables read from a config file. The environment is shared by tests
+    /// running in parallel, so every test uses variable names of its own.
+    fn vars(file: &[(&str, &str)]) -> Vars {
+        Vars {
+            file: file
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        App::new("test")
+            .arg(Arg::with_name("flag").long("flag"))
+            .arg(
+                Arg::with_name("portal-band")
+                    .long("portal-band")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("portal-channel")
+                    .long("portal-channel")
+                    .takes_value(true),
+            )
+            .get_matches_from(Some("test").iter().chain(args))
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_config_file() {
+        env::set_var("WIFI_CONNECT_TEST_PRECEDENCE", "environment");
+
+        let vars = vars(&[
+            ("WIFI_CONNECT_TEST_PRECEDENCE", "file"),
+            ("WIFI_CONNECT_TEST_FILE_ONLY", "file"),
+        ]);
+
+        assert_eq!(
+            vars.var("WIFI_CONNECT_TEST_PRECEDENCE").unwrap(),
+            "environment"
+        );
+        assert_eq!(vars.var("WIFI_CONNECT_TEST_FILE_ONLY").unwrap(), "file");
+        assert!(vars.var("WIFI_CONNECT_TEST_UNSET").is_err());
+    }
+
+    #[test]
+    fn flag_from_command_line() {
+        assert!(get_flag(
+            &matches(&["--flag"]),
+            &vars(&[("WIFI_CONNECT_TEST_FLAG_ARG", "false")]),
+            "flag",
+            "WIFI_CONNECT_TEST_FLAG_ARG"
+        ));
+    }
+
+    #[test]
+    fn flag_from_environment_or_config_file() {
+        env::set_var("WIFI_CONNECT_TEST_FLAG_ENV", "yes");
+
+        assert!(get_flag(
+            &matches(&[]),
+            &vars(&[]),
+            "flag",
+            "WIFI_CONNECT_TEST_FLAG_ENV"
+        ));
+        assert!(get_flag(
+            &matches(&[]),
+            &vars(&[("WIFI_CONNECT_TEST_FLAG_FILE", "1")]),
+            "flag",
+            "WIFI_CONNECT_TEST_FLAG_FILE"
+        ));
+        assert!(!get_flag(
+            &matches(&[]),
+            &vars(&[("WIFI_CONNECT_TEST_FLAG_OFF", "0")]),
+            "flag",
+            "WIFI_CONNECT_TEST_FLAG_OFF"
+        ));
+        assert!(!get_flag(
+            &matches(&[]),
+            &vars(&[]),
+            "flag",
+            "WIFI_CONNECT_TEST_FLAG_UNSET"
+        ));
+    }
+
+    #[test]
+    fn truthy_values() {
+        assert!(is_truthy("1"));
+        assert!(is_truthy("true"));
+        assert!(is_truthy(" YES "));
+        assert!(!is_truthy("0"));
+        assert!(!is_truthy("no"));
+        assert!(!is_truthy(""));
+    }
+
+    #[test]
+    fn config_file_lines() {
+        let path = env::temp_dir().join(format!("wifi-connect-config-{}", process::id()));
+
+        fs::write(
+            &path,
+            "# comment\n\nPORTAL_SSID = \"My Device\"\nPORTAL_PASSPHRASE='secret'\nDBUS_BUS=session\n",
+        )
+        .unwrap();
+
+        let vars = read_config_file(&path).unwrap();
+
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["PORTAL_SSID"], "My Device");
+        assert_eq!(vars["PORTAL_PASSPHRASE"], "secret");
+        assert_eq!(vars["DBUS_BUS"], "session");
+
+        fs::write(&path, "PORTAL_SSID\n").unwrap();
+        assert!(read_config_file(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(read_config_file(&path).is_err());
+    }
+
+    #[test]
+    fn portal_band_is_derived_from_channel() {
+        assert_eq!(
+            get_portal_band_and_channel(&matches(&["--portal-channel", "6"]), &vars(&[])).unwrap(),
+            (
+                Some(PortalBand::Band2_4Ghz),
+                Some(PortalChannel::Channel(6))
+            )
+        );
+        assert_eq!(
+            get_portal_band_and_channel(&matches(&["--portal-channel", "36"]), &vars(&[])).unwrap(),
+            (Some(PortalBand::Band5Ghz), Some(PortalChannel::Channel(36)))
+        );
+    }
+
+    #[test]
+    fn automatic_portal_band_implies_automatic_channel() {
+        assert_eq!(
+            get_portal_band_and_channel(&matches(&["--portal-band", "auto"]), &vars(&[])).unwrap(),
+            (Some(PortalBand::Auto), Some(PortalChannel::Auto))
+        );
+        assert!(get_portal_band_and_channel(
+            &matches(&["--portal-band", "auto", "--portal-channel", "6"]),
+            &vars(&[])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn portal_channel_must_be_in_portal_band() {
+        assert!(get_portal_band_and_channel(
+            &matches(&["--portal-band", "5", "--portal-channel", "6"]),
+            &vars(&[])
+        )
+        .is_err());
+        assert!(
+            get_portal_band_and_channel(&matches(&["--portal-channel", "20"]), &vars(&[])).is_err()
+        );
+        assert!(
+            get_portal_band_and_channel(&matches(&["--portal-channel", "six"]), &vars(&[]))
+                .is_err()
+        );
+    }
+}
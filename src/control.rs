@@ -0,0 +1,399 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use errors::*;
+use logger::{log_levels, set_log_level};
+use network::{reply_channel, NetworkCommand, NetworkCommandResponse, ReplyTx};
+
+/// Starting the portal and rescanning may take a while, as the network thread
+/// waits for NetworkManager
+const CONTROL_COMMAND_TIMEOUT_SECS: u64 = 60;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// JSON-RPC 2.0 over a Unix socket with one request or response per line.
+/// The socket is available independently of the captive portal, so that
+/// applications on the device may start it.
+pub fn start_control_server(path: &Path, network_tx: Sender<NetworkCommand>) -> Result<()> {
+    let listener = bind_control_socket(path)
+        .chain_err(|| ErrorKind::ControlSocket(path.display().to_string()))?;
+
+    info!("Control socket listening on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let network_tx = network_tx.clone();
+                    thread::spawn(move || handle_client(stream, &network_tx));
+                }
+                Err(e) => warn!("Accepting control socket connection failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+pub fn stop_control_server(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        debug!("Removing control socket failed: {}", e);
+    }
+}
+
+fn bind_control_socket(path: &Path) -> Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // A socket left over from a previous run would make binding fail
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+
+    Ok(listener)
+}
+
+fn handle_client(stream: UnixStream, network_tx: &Sender<NetworkCommand>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("Cloning control socket stream failed: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match handle_request(&line, network_tx) {
+            Some(response) => response,
+            None => continue,
+        };
+
+        let written = serde_json::to_string(&response)
+            .map_err(io::Error::from)
+            .and_then(|json| writeln!(writer, "{}", json));
+
+        if let Err(e) = written {
+            debug!("Writing control socket response failed: {}", e);
+            return;
+        }
+    }
+}
+
+/// Notifications, i.e. requests without an id, are not replied to
+fn handle_request(line: &str, network_tx: &Sender<NetworkCommand>) -> Option<RpcResponse> {
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, &e.to_string()),
+            ))
+        }
+    };
+
+    let request: RpcRequest = match serde_json::from_value(value) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, &e.to_string()),
+            ))
+        }
+    };
+
+    debug!("Control request: {}", request.method);
+
+    let result = dispatch(&request.method, &request.params, network_tx);
+
+    let id = request.id?;
+
+    Some(match result {
+        Ok(result) => RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(error) => error_response(id, error),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> RpcResponse {
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(error),
+    }
+}
+
+fn dispatch(
+    method: &str,
+    params: &Value,
+    network_tx: &Sender<NetworkCommand>,
+) -> ::std::result::Result<Value, RpcError> {
+    let response = match method {
+        "start_portal" => send_command(network_tx, |reply_tx| NetworkCommand::StartPortal {
+            reply_tx,
+        })?,
+        "stop_portal" => send_command(network_tx, |reply_tx| NetworkCommand::StopPortal {
+            reply_tx,
+        })?,
+        "get_state" | "get_last_result" => {
+            send_command(network_tx, |reply_tx| NetworkCommand::Status { reply_tx })?
+        }
+        "list_saved_networks" => send_command(network_tx, |reply_tx| {
+            NetworkCommand::SavedNetworks { reply_tx }
+        })?,
+        "forget_network" => {
+            let ssid = match params.get("ssid").and_then(Value::as_str) {
+                Some(ssid) => ssid.to_string(),
+                None => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "Expected an 'ssid' parameter",
+                    ))
+                }
+            };
+
+            send_command(network_tx, |reply_tx| NetworkCommand::ForgetNetwork {
+                ssid,
+                reply_tx,
+            })?
+        }
+        "rescan" => send_command(network_tx, |reply_tx| NetworkCommand::Rescan { reply_tx })?,
+        "get_log_levels" => return Ok(Value::String(log_levels())),
+        "set_log_level" => {
+            let level = match params
+                .get("level")
+                .and_then(Value::as_str)
+                .and_then(|level| level.parse().ok())
+            {
+                Some(level) => level,
+                None => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "Expected a 'level' parameter: off, error, warn, info, debug or trace",
+                    ))
+                }
+            };
+
+            let target = params.get("target").and_then(Value::as_str);
+
+            set_log_level(target, level);
+
+            return Ok(Value::String(log_levels()));
+        }
+        _ => {
+            let message = format!("Unknown method '{}'", method);
+            return Err(RpcError::new(METHOD_NOT_FOUND, &message));
+        }
+    };
+
+    command_result(method, response)
+}
+
+fn command_result(
+    method: &str,
+    response: NetworkCommandResponse,
+) -> ::std::result::Result<Value, RpcError> {
+    let result = match response {
+        NetworkCommandResponse::Done => Ok(Value::Bool(true)),
+        NetworkCommandResponse::Networks(networks) => serde_json::to_value(networks),
+        NetworkCommandResponse::SavedNetworks(networks) => serde_json::to_value(networks),
+        NetworkCommandResponse::Status(status) => {
+            if method == "get_last_result" {
+                serde_json::to_value(status.last_connection_attempt)
+            } else {
+                serde_json::to_value(status)
+            }
+        }
+        NetworkCommandResponse::Failed(reason) => {
+            return Err(RpcError::new(COMMAND_FAILED, &reason))
+        }
+        _ => {
+            return Err(RpcError::new(
+                COMMAND_FAILED,
+                "Unexpected network command response",
+            ))
+        }
+    };
+
+    result.map_err(|e| RpcError::new(COMMAND_FAILED, &e.to_string()))
+}
+
+fn send_command<F>(
+    network_tx: &Sender<NetworkCommand>,
+    command: F,
+) -> ::std::result::Result<NetworkCommandResponse, RpcError>
+where
+    F: FnOnce(ReplyTx) -> NetworkCommand,
+{
+    let (reply_tx, reply_rx) = reply_channel();
+
+    if network_tx.send(command(reply_tx)).is_err() {
+        return Err(RpcError::new(
+            COMMAND_FAILED,
+            "Network thread is not running",
+        ));
+    }
+
+    reply_rx
+        .recv_timeout(Duration::from_secs(CONTROL_COMMAND_TIMEOUT_SECS))
+        .map_err(|_| RpcError::new(COMMAND_FAILED, "No response from the network thread"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    fn error_code(response: Option<RpcResponse>) -> Option<i64> {
+        response
+            .and_then(|response| response.error)
+            .map(|error| error.code)
+    }
+
+    fn request(line: &str) -> Option<RpcResponse> {
+        let (network_tx, _network_rx) = channel();
+        handle_request(line, &network_tx)
+    }
+
+    #[test]
+    fn malformed_json_is_a_parse_error() {
+        let response = request("{\"method\":").unwrap();
+
+        assert_eq!(response.id, Value::Null);
+        assert_eq!(error_code(Some(response)), Some(PARSE_ERROR));
+    }
+
+    #[test]
+    fn request_without_method_is_invalid() {
+        assert_eq!(error_code(request(r#"{"id":1}"#)), Some(INVALID_REQUEST));
+        assert_eq!(error_code(request("[]")), Some(INVALID_REQUEST));
+    }
+
+    #[test]
+    fn unknown_method() {
+        let response = request(r#"{"id":"a","method":"reboot"}"#).unwrap();
+
+        assert_eq!(response.id, Value::from("a"));
+        assert_eq!(error_code(Some(response)), Some(METHOD_NOT_FOUND));
+    }
+
+    #[test]
+    fn missing_params() {
+        assert_eq!(
+            error_code(request(r#"{"id":1,"method":"forget_network"}"#)),
+            Some(INVALID_PARAMS)
+        );
+        assert_eq!(
+            error_code(request(
+                r#"{"id":1,"method":"set_log_level","params":{"level":"loud"}}"#
+            )),
+            Some(INVALID_PARAMS)
+        );
+    }
+
+    #[test]
+    fn notifications_are_not_replied_to() {
+        assert!(request(r#"{"method":"reboot"}"#).is_none());
+    }
+
+    #[test]
+    fn stopped_network_thread() {
+        let (network_tx, network_rx) = channel();
+        drop(network_rx);
+
+        assert_eq!(
+            error_code(handle_request(
+                r#"{"id":1,"method":"start_portal"}"#,
+                &network_tx
+            )),
+            Some(COMMAND_FAILED)
+        );
+    }
+
+    #[test]
+    fn network_command_responses() {
+        assert_eq!(
+            command_result("stop_portal", NetworkCommandResponse::Done).ok(),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(
+            command_result(
+                "start_portal",
+                NetworkCommandResponse::Failed("No WiFi device".into())
+            )
+            .err()
+            .map(|error| (error.code, error.message)),
+            Some((COMMAND_FAILED, "No WiFi device".to_string()))
+        );
+        assert_eq!(
+            command_result("rescan", NetworkCommandResponse::Connecting)
+                .err()
+                .map(|error| error.code),
+            Some(COMMAND_FAILED)
+        );
+    }
+}
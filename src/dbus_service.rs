@@ -0,0 +1,255 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use dbus::tree::{Factory, MTFn, MethodErr, MethodInfo, MethodResult, Tree};
+use dbus::{BusType, Connection, Interface, Member, Message, NameFlag, Path};
+
+use config::DbusBus;
+use errors::*;
+use events::{Event, EventBus};
+use network::{reply_channel, NetworkCommand, NetworkCommandResponse, ReplyTx, State};
+
+const SERVICE_NAME: &str = "io.balena.WifiConnect";
+const OBJECT_PATH: &str = "/io/balena/WifiConnect";
+const INTERFACE_NAME: &str = "io.balena.WifiConnect";
+
+/// Below the default D-Bus method call timeout of 25 seconds
+const DBUS_COMMAND_TIMEOUT_SECS: u64 = 20;
+
+/// How often pending events are turned into signals when the bus is idle
+const DBUS_POLL_INTERVAL_MS: i32 = 500;
+
+/// Property values are kept up to date from events, so that reading them does
+/// not wait for the network thread
+struct Properties {
+    state: State,
+    portal_ssid: String,
+    last_error: String,
+}
+
+type SharedProperties = Arc<Mutex<Properties>>;
+
+type ServiceTree = Tree<MTFn<()>, ()>;
+
+/// Exports `io.balena.WifiConnect` at `/io/balena/WifiConnect`. The D-Bus
+/// connection is not `Send` and lives in its own thread, which reports back
+/// once the name is acquired.
+pub fn start_dbus_service(
+    bus: DbusBus,
+    portal_ssid: &str,
+    network_tx: Sender<NetworkCommand>,
+    events: &EventBus,
+) -> Result<()> {
+    let properties = Arc::new(Mutex::new(Properties {
+        state: State::Idle,
+        portal_ssid: portal_ssid.to_string(),
+        last_error: String::new(),
+    }));
+
+    let event_rx = events.subscribe();
+
+    let (ready_tx, ready_rx) = channel();
+
+    thread::spawn(move || {
+        let (connection, tree) = match register_service(bus, &network_tx, &properties) {
+            Ok(service) => {
+                let _ = ready_tx.send(Ok(()));
+                service
+            }
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+
+        for _ in tree.run(&connection, connection.iter(DBUS_POLL_INTERVAL_MS)) {
+            while let Ok(event) = event_rx.try_recv() {
+                handle_event(&connection, &properties, event);
+            }
+        }
+    });
+
+    ready_rx.recv()?
+}
+
+fn register_service(
+    bus: DbusBus,
+    network_tx: &Sender<NetworkCommand>,
+    properties: &SharedProperties,
+) -> Result<(Connection, ServiceTree)> {
+    let bus_type = match bus {
+        DbusBus::System => BusType::System,
+        DbusBus::Session => BusType::Session,
+    };
+
+    let connection = Connection::get_private(bus_type).chain_err(|| ErrorKind::DBusService)?;
+
+    connection
+        .register_name(SERVICE_NAME, NameFlag::DoNotQueue as u32)
+        .chain_err(|| ErrorKind::DBusService)?;
+
+    let tree = create_tree(network_tx, properties);
+
+    tree.set_registered(&connection, true)
+        .chain_err(|| ErrorKind::DBusService)?;
+
+    info!("D-Bus service {} registered", SERVICE_NAME);
+
+    Ok((connection, tree))
+}
+
+fn create_tree(network_tx: &Sender<NetworkCommand>, properties: &SharedProperties) -> ServiceTree {
+    let f = Factory::new_fn::<()>();
+
+    let start_tx = network_tx.clone();
+    let start_properties = properties.clone();
+    let stop_tx = network_tx.clone();
+    let connect_tx = network_tx.clone();
+    let forget_tx = network_tx.clone();
+
+    let state = properties.clone();
+    let portal_ssid = properties.clone();
+    let last_error = properties.clone();
+
+    let interface = f
+        .interface(INTERFACE_NAME, ())
+        .add_m(f.method("StartPortal", (), move |m| {
+            start_portal(&start_tx, &start_properties)?;
+            method_return(m)
+        }))
+        .add_m(f.method("StopPortal", (), move |m| {
+            send_command(&stop_tx, |reply_tx| NetworkCommand::StopPortal { reply_tx })?;
+            method_return(m)
+        }))
+        .add_m(
+            f.method("Connect", (), move |m| {
+                let (ssid, identity, passphrase) = m.msg.read3::<&str, &str, &str>()?;
+                send_command(&connect_tx, |reply_tx| NetworkCommand::Connect {
+                    ssid: ssid.to_string(),
+                    identity: identity.to_string(),
+                    passphrase: passphrase.to_string(),
+                    reply_tx,
+                })?;
+                method_return(m)
+            })
+            .inarg::<&str, _>("ssid")
+            .inarg::<&str, _>("identity")
+            .inarg::<&str, _>("passphrase"),
+        )
+        .add_m(
+            f.method("Forget", (), move |m| {
+                let ssid = m.msg.read1::<&str>()?;
+                send_command(&forget_tx, |reply_tx| NetworkCommand::ForgetNetwork {
+                    ssid: ssid.to_string(),
+                    reply_tx,
+                })?;
+                method_return(m)
+            })
+            .inarg::<&str, _>("ssid"),
+        )
+        .add_p(f.property::<&str, _>("State", ()).on_get(move |i, _| {
+            i.append(state.lock().unwrap().state.as_str());
+            Ok(())
+        }))
+        .add_p(f.property::<&str, _>("PortalSSID", ()).on_get(move |i, _| {
+            i.append(portal_ssid.lock().unwrap().portal_ssid.as_str());
+            Ok(())
+        }))
+        .add_p(f.property::<&str, _>("LastError", ()).on_get(move |i, _| {
+            i.append(last_error.lock().unwrap().last_error.as_str());
+            Ok(())
+        }))
+        .add_s(f.signal("StateChanged", ()).sarg::<&str, _>("state"));
+
+    f.tree(()).add(
+        f.object_path(OBJECT_PATH, ())
+            .introspectable()
+            .add(interface),
+    )
+}
+
+fn method_return(m: &MethodInfo<MTFn<()>, ()>) -> MethodResult {
+    Ok(vec![m.msg.method_return()])
+}
+
+/// Starting the portal scans first and may take longer than D-Bus callers
+/// wait, so the method returns right away. The outcome is reported by the
+/// `StateChanged` signal, and a failure in `LastError`.
+fn start_portal(
+    network_tx: &Sender<NetworkCommand>,
+    properties: &SharedProperties,
+) -> ::std::result::Result<(), MethodErr> {
+    let (reply_tx, reply_rx) = reply_channel();
+
+    if network_tx
+        .send(NetworkCommand::StartPortal { reply_tx })
+        .is_err()
+    {
+        return Err(MethodErr::failed(&"Network thread is not running"));
+    }
+
+    properties.lock().unwrap().last_error.clear();
+
+    let properties = properties.clone();
+
+    thread::spawn(move || {
+        if let Ok(NetworkCommandResponse::Failed(reason)) = reply_rx.recv() {
+            warn!("Starting the portal over D-Bus failed: {}", reason);
+            properties.lock().unwrap().last_error = reason;
+        }
+    });
+
+    Ok(())
+}
+
+/// Other methods wait for the network thread, as the HTTP and control APIs do
+fn send_command<F>(
+    network_tx: &Sender<NetworkCommand>,
+    command: F,
+) -> ::std::result::Result<NetworkCommandResponse, MethodErr>
+where
+    F: FnOnce(ReplyTx) -> NetworkCommand,
+{
+    let (reply_tx, reply_rx) = reply_channel();
+
+    if network_tx.send(command(reply_tx)).is_err() {
+        return Err(MethodErr::failed(&"Network thread is not running"));
+    }
+
+    let response = reply_rx
+        .recv_timeout(Duration::from_secs(DBUS_COMMAND_TIMEOUT_SECS))
+        .map_err(|_| MethodErr::failed(&"No response from the network thread"))?;
+
+    match response {
+        NetworkCommandResponse::InvalidCredentials(e) => Err(MethodErr::failed(&e.message)),
+        NetworkCommandResponse::Failed(reason) => Err(MethodErr::failed(&reason)),
+        response => Ok(response),
+    }
+}
+
+fn handle_event(connection: &Connection, properties: &SharedProperties, event: Event) {
+    let mut properties = properties.lock().unwrap();
+
+    match event {
+        Event::StateChanged { state } => {
+            properties.state = state;
+
+            let signal = Message::signal(
+                &Path::from(OBJECT_PATH),
+                &Interface::from(INTERFACE_NAME),
+                &Member::from("StateChanged"),
+            )
+            .append1(state.as_str());
+
+            if connection.send(signal).is_err() {
+                warn!("Sending D-Bus StateChanged signal failed");
+            }
+        }
+        Event::PortalStarted { ssid } => properties.portal_ssid = ssid,
+        Event::ConnectStarted { .. } => properties.last_error.clear(),
+        Event::ConnectFailed { reason, .. } => properties.last_error = reason,
+        _ => {}
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use nm_dbus::wireless_capabilities;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// `NM_WIFI_DEVICE_CAP_AP`
+const WIFI_DEVICE_CAP_AP: u32 = 0x40;
+
+/// `NM_WIFI_DEVICE_CAP_FREQ_5GHZ`
+const WIFI_DEVICE_CAP_FREQ_5GHZ: u32 = 0x400;
+
+/// Selects the WiFi device by interface name, `mac=<address>` or
+/// `driver=<name>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    Interface(String),
+    MacAddress(String),
+    Driver(String),
+}
+
+impl DeviceSelector {
+    pub fn parse(value: &str) -> Self {
+        if let Some(address) = value.strip_prefix("mac=") {
+            DeviceSelector::MacAddress(address.to_lowercase())
+        } else if let Some(driver) = value.strip_prefix("driver=") {
+            DeviceSelector::Driver(driver.to_string())
+        } else {
+            DeviceSelector::Interface(value.to_string())
+        }
+    }
+
+    pub fn matches(&self, interface: &str) -> bool {
+        match *self {
+            DeviceSelector::Interface(ref name) => name == interface,
+            DeviceSelector::MacAddress(ref address) => {
+                mac_address(interface).as_ref() == Some(address)
+            }
+            DeviceSelector::Driver(ref name) => driver(interface).as_ref() == Some(name),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceSelector::Interface(ref name) => write!(f, "{}", name),
+            DeviceSelector::MacAddress(ref address) => write!(f, "mac={}", address),
+            DeviceSelector::Driver(ref name) => write!(f, "driver={}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+pub struct DeviceCapabilities {
+    /// Can run the captive portal hotspot
+    pub access_point: bool,
+    pub band_5ghz: bool,
+    /// Can stay connected to a network while running the hotspot
+    pub ap_sta_concurrency: bool,
+}
+
+/// Devices are assumed to support AP mode if NetworkManager cannot tell
+pub fn device_capabilities(interface: &str) -> DeviceCapabilities {
+    let (access_point, band_5ghz) = match wireless_capabilities(interface) {
+        Some(flags) => (
+            flags & WIFI_DEVICE_CAP_AP != 0,
+            flags & WIFI_DEVICE_CAP_FREQ_5GHZ != 0,
+        ),
+        None => (true, false),
+    };
+
+    DeviceCapabilities {
+        access_point,
+        band_5ghz,
+        ap_sta_concurrency: supports_ap_sta_concurrency(interface),
+    }
+}
+
+pub fn mac_address(interface: &str) -> Option<String> {
+    fs::read_to_string(interface_path(interface).join("address"))
+        .ok()
+        .map(|address| address.trim().to_lowercase())
+}
+
+pub fn driver(interface: &str) -> Option<String> {
+    fs::read_link(interface_path(interface).join("device/driver"))
+        .ok()
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+}
+
+fn interface_path(interface: &str) -> PathBuf {
+    PathBuf::from(SYS_CLASS_NET).join(interface)
+}
+
+/// NetworkManager does not expose interface combinations, so they are read
+/// from `iw phy <phy> info`
+fn supports_ap_sta_concurrency(interface: &str) -> bool {
+    let phy = match fs::read_to_string(interface_path(interface).join("phy80211/name")) {
+        Ok(phy) => phy.trim().to_string(),
+        Err(_) => return false,
+    };
+
+    let output = match Command::new("iw").args(["phy", &phy, "info"]).output() {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Ok(output) => {
+            warn!(
+                "Reading interface combinations of {} failed, assuming no AP/STA concurrency: {}",
+                phy, output.status
+            );
+            return false;
+        }
+        Err(e) => {
+            warn!(
+                "Cannot run iw, assuming no AP/STA concurrency for {}: {}",
+                phy, e
+            );
+            return false;
+        }
+    };
+
+    has_ap_sta_combination(&output)
+}
+
+/// `iw` continues every combination on a second line, e.g.
+///
+/// ```text
+/// valid interface combinations:
+///      * #{ managed } <= 1, #{ AP, P2P-client, P2P-GO } <= 1,
+///        total <= 3, #channels <= 2
+/// ```
+fn has_ap_sta_combination(info: &str) -> bool {
+    let mut combinations: Vec<String> = Vec::new();
+
+    for line in info
+        .lines()
+        .skip_while(|line| !line.contains("valid interface combinations"))
+        .skip(1)
+        .map(str::trim)
+    {
+        if line.starts_with('*') {
+            combinations.push(line.to_string());
+        } else if line.starts_with("total") {
+            if let Some(combination) = combinations.last_mut() {
+                combination.push(' ');
+                combination.push_str(line);
+            }
+        } else {
+            break;
+        }
+    }
+
+    combinations
+        .iter()
+        .any(|combination| is_ap_sta_combination(combination))
+}
+
+fn is_ap_sta_combination(line: &str) -> bool {
+    let groups = line
+        .split("#{")
+        .skip(1)
+        .map(|group| group.split('}').next().unwrap_or(""))
+        .collect::<Vec<_>>();
+
+    let has_group = |interface_type: &str| {
+        groups
+            .iter()
+            .any(|group| group.split(',').any(|t| t.trim() == interface_type))
+    };
+
+    let total = line
+        .split("total <=")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .and_then(|total| total.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+
+    has_group("managed") && has_group("AP") && total >= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shortened `iw phy phy0 info` output of a Raspberry Pi
+    const IW_INFO: &str = "Wiphy phy0
+\tmax # scan SSIDs: 10
+\tSupported interface modes:
+\t\t * IBSS
+\t\t * managed
+\t\t * AP
+\tvalid interface combinations:
+\t\t * #{ managed } <= 1, #{ P2P-device } <= 1, #{ P2P-client, P2P-GO } <= 1,
+\t\t   total <= 3, #channels <= 2
+\t\t * #{ managed } <= 1, #{ AP } <= 1, #{ P2P-client } <= 1, #{ P2P-device } <= 1,
+\t\t   total <= 4, #channels <= 1
+\tDevice supports scan flush.
+";
+
+    #[test]
+    fn ap_sta_combination() {
+        assert!(is_ap_sta_combination(
+            "* #{ managed } <= 1, #{ AP, P2P-client, P2P-GO } <= 1, total <= 3, #channels <= 2"
+        ));
+    }
+
+    #[test]
+    fn combination_without_ap() {
+        assert!(!is_ap_sta_combination(
+            "* #{ managed } <= 1, #{ P2P-client, P2P-GO } <= 1, total <= 2, #channels <= 1"
+        ));
+    }
+
+    #[test]
+    fn combination_of_one_interface() {
+        assert!(!is_ap_sta_combination(
+            "* #{ managed, AP } <= 1, total <= 1, #channels <= 1"
+        ));
+        assert!(!is_ap_sta_combination("* #{ managed } <= 1, #{ AP } <= 1"));
+    }
+
+    #[test]
+    fn iw_info_with_ap_sta_combination() {
+        assert!(has_ap_sta_combination(IW_INFO));
+    }
+
+    #[test]
+    fn iw_info_without_ap_sta_combination() {
+        let info = IW_INFO.replace("#{ AP } <= 1, ", "");
+
+        assert!(!has_ap_sta_combination(&info));
+        assert!(!has_ap_sta_combination("Wiphy phy0\n"));
+    }
+
+    #[test]
+    fn supported_interface_modes_are_not_combinations() {
+        let info = IW_INFO.replace(
+            "valid interface combinations",
+            "interface combinations are not supported",
+        );
+
+        assert!(!has_ap_sta_combination(&info));
+    }
+}
@@ -18,10 +18,15 @@ pub fn start_dnsmasq(config: &Config, device: &Device) -> Result<Child> {
         "--no-hosts",
     ];
 
-    Command::new("dnsmasq")
-        .args(args)
-        .spawn()
-        .chain_err(|| ErrorKind::Dnsmasq)
+    let mut command = Command::new("dnsmasq");
+    command.args(args);
+
+    // Without root dnsmasq cannot write its default PID file
+    if config.capabilities {
+        command.arg("--pid-file");
+    }
+
+    command.spawn().chain_err(|| ErrorKind::Dnsmasq)
 }
 
 pub fn stop_dnsmasq(dnsmasq: &mut Child) -> Result<()> {
@@ -8,6 +8,7 @@ error_chain! {
         Recv(::std::sync::mpsc::RecvError);
         SendNetworkCommand(::std::sync::mpsc::SendError<network::NetworkCommand>);
         Nix(::nix::Error);
+        Ssl(::openssl::error::ErrorStack);
     }
 
     links {
@@ -15,30 +16,10 @@ error_chain! {
     }
 
     errors {
-        RecvAccessPointSSIDs {
-            description("Receiving access point SSIDs failed")
-        }
-
-        SendAccessPointSSIDs {
-            description("Sending access point SSIDs failed")
-        }
-
-        SerializeAccessPointSSIDs {
-            description("Serializing access point SSIDs failed")
-        }
-
         RecvNetworkCommand {
             description("Receiving network command failed")
         }
 
-        SendNetworkCommandActivate {
-            description("Sending NetworkCommand::Activate failed")
-        }
-
-        SendNetworkCommandConnect {
-            description("Sending NetworkCommand::Connect failed")
-        }
-
         DeviceByInterface(interface: String) {
             description("Cannot find network device with interface name")
             display("Cannot find network device with interface name '{}'", interface)
@@ -103,18 +84,135 @@ error_chain! {
             description("Root privileges required")
             display("You need root privileges to run {}", app)
         }
+
+        CapabilitiesRequired(app: String, missing: String) {
+            description("Capabilities required")
+            display("You need root privileges or the {} capabilities to run {}", missing, app)
+        }
+
+        ChangeCapabilities {
+            description("Changing capabilities failed")
+        }
+
+        NetworkManagerNotRunning(timeout: u64) {
+            description("NetworkManager is not running")
+            display("NetworkManager did not appear on D-Bus within {} seconds", timeout)
+        }
+
+        NetworkManagerRecovery {
+            description("Recovering from a NetworkManager restart failed")
+        }
+
+        RadioHardBlocked(name: String) {
+            description("WiFi is hard blocked by rfkill")
+            display("WiFi is hard blocked by rfkill switch '{}'. Turn on the hardware radio switch", name)
+        }
+
+        RadioSoftBlocked(name: String) {
+            description("WiFi is soft blocked by rfkill")
+            display("WiFi is soft blocked by rfkill switch '{}'. Unblock it with 'rfkill unblock wifi' or run with --unblock-radio", name)
+        }
+
+        RadioUnblock(name: String) {
+            description("Unblocking rfkill switch failed")
+            display("Unblocking rfkill switch '{}' failed", name)
+        }
+
+        WirelessDisabled {
+            description("WiFi is disabled in NetworkManager. Enable it with 'nmcli radio wifi on' or run with --unblock-radio")
+        }
+
+        EnableWireless {
+            description("Enabling WiFi in NetworkManager failed")
+        }
+
+        NoAccessPointMode(interface: String) {
+            description("WiFi device does not support access point mode")
+            display("WiFi device does not support access point mode: {}", interface)
+        }
+
+        DeviceBySelector(selector: String) {
+            description("Cannot find a WiFi device matching the selector")
+            display("Cannot find a WiFi device matching '{}'", selector)
+        }
+
+        ManageDevice(interface: String) {
+            description("Making the device managed by NetworkManager failed")
+            display("Making {} managed by NetworkManager failed", interface)
+        }
+
+        SetRegulatoryDomain(country: String) {
+            description("Setting the regulatory domain failed")
+            display("Setting the regulatory domain {} failed", country)
+        }
+
+        InvalidConfig(message: String) {
+            description("Invalid configuration")
+            display("Invalid configuration: {}", message)
+        }
+
+        No5GhzSupport(interface: String) {
+            description("WiFi device does not support the 5GHz band")
+            display("WiFi device does not support the 5GHz portal band: {}", interface)
+        }
+
+        DirectoryNotWritable(directory: String) {
+            description("Directory not writable")
+            display("Directory {} is not writable without root privileges", directory)
+        }
+
+        SameTargetInterface(interface: String) {
+            description("The portal and target interfaces should be different")
+            display("The portal and target interfaces should be different, both are {}", interface)
+        }
+
+        TlsIncompleteKeyPair {
+            description("Both a TLS certificate and a TLS key should be specified")
+        }
+
+        TlsLoadCertificate(path: String) {
+            description("Loading the TLS certificate failed")
+            display("Loading the TLS certificate '{}' failed", path)
+        }
+
+        TlsGenerateCertificate {
+            description("Generating a self-signed TLS certificate failed")
+        }
+
+        TlsAcceptor {
+            description("Configuring the TLS acceptor failed")
+        }
+
+        WriteAccessCode(path: String) {
+            description("Writing the portal access code failed")
+            display("Writing the portal access code to '{}' failed", path)
+        }
+
+        ControlSocket(path: String) {
+            description("Binding the control socket failed")
+            display("Binding the control socket '{}' failed", path)
+        }
+
+        DBusService {
+            description("Registering the D-Bus service failed")
+        }
+
+        HookUrl(url: String) {
+            description("Cannot parse hook URL")
+            display("Cannot parse hook URL '{}'", url)
+        }
+
+        LogFile(path: String) {
+            description("Opening the log file failed")
+            display("Opening the log file '{}' failed", path)
+        }
     }
 }
 
 pub fn exit_code(e: &Error) -> i32 {
     match *e.kind() {
         ErrorKind::Dnsmasq => 3,
-        ErrorKind::RecvAccessPointSSIDs => 4,
-        ErrorKind::SendAccessPointSSIDs => 5,
-        ErrorKind::SerializeAccessPointSSIDs => 6,
         ErrorKind::RecvNetworkCommand => 7,
-        ErrorKind::SendNetworkCommandActivate => 8,
-        ErrorKind::SendNetworkCommandConnect => 9,
         ErrorKind::DeviceByInterface(_) => 10,
         ErrorKind::NotAWiFiDevice(_) => 11,
         ErrorKind::NoWiFiDevice => 12,
@@ -129,6 +227,32 @@ pub fn exit_code(e: &Error) -> i32 {
         ErrorKind::TrapExitSignals => 22,
         ErrorKind::RootPrivilegesRequired(_) => 23,
         ErrorKind::UnmanagedDevice(_) => 24,
+        ErrorKind::TlsIncompleteKeyPair => 25,
+        ErrorKind::TlsLoadCertificate(_) => 26,
+        ErrorKind::TlsGenerateCertificate => 27,
+        ErrorKind::TlsAcceptor => 28,
+        ErrorKind::WriteAccessCode(_) => 29,
+        ErrorKind::ControlSocket(_) => 30,
+        ErrorKind::DBusService => 31,
+        ErrorKind::HookUrl(_) => 32,
+        ErrorKind::LogFile(_) => 33,
+        ErrorKind::CapabilitiesRequired(_, _) => 34,
+        ErrorKind::ChangeCapabilities => 35,
+        ErrorKind::NetworkManagerNotRunning(_) => 36,
+        ErrorKind::NetworkManagerRecovery => 37,
+        ErrorKind::RadioHardBlocked(_) => 38,
+        ErrorKind::RadioSoftBlocked(_) => 39,
+        ErrorKind::RadioUnblock(_) => 40,
+        ErrorKind::WirelessDisabled => 41,
+        ErrorKind::EnableWireless => 42,
+        ErrorKind::NoAccessPointMode(_) => 43,
+        ErrorKind::DeviceBySelector(_) => 44,
+        ErrorKind::ManageDevice(_) => 45,
+        ErrorKind::SameTargetInterface(_) => 46,
+        ErrorKind::SetRegulatoryDomain(_) => 47,
+        ErrorKind::InvalidConfig(_) => 48,
+        ErrorKind::DirectoryNotWritable(_) => 49,
+        ErrorKind::No5GhzSupport(_) => 50,
         _ => 1,
     }
 }
@@ -0,0 +1,116 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use iron::response::WriteBody;
+use serde_json;
+
+use network::{Network, State};
+
+/// Comment lines are sent periodically, so that broken connections are
+/// detected and their subscriptions dropped
+const KEEPALIVE_INTERVAL_SECS: u64 = 15;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PortalStarted { ssid: String },
+    Activated,
+    ScanResults { networks: Vec<Network> },
+    ConnectStarted { ssid: String },
+    StateChanged { state: State },
+    Connected { ssid: String },
+    ConnectFailed { ssid: String, reason: String },
+    PortalStopping { seconds: u64 },
+    TimeoutApproaching { seconds: u64 },
+    Timeout,
+    Exit,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Event::PortalStarted { .. } => "portal_started",
+            Event::Activated => "activated",
+            Event::ScanResults { .. } => "scan_results",
+            Event::ConnectStarted { .. } => "connect_started",
+            Event::StateChanged { .. } => "state_changed",
+            Event::Connected { .. } => "connected",
+            Event::ConnectFailed { .. } => "connect_failed",
+            Event::PortalStopping { .. } => "portal_stopping",
+            Event::TimeoutApproaching { .. } => "timeout_approaching",
+            Event::Timeout => "timeout",
+            Event::Exit => "exit",
+        }
+    }
+}
+
+/// Events are published by the network thread and delivered to every
+/// subscriber. Subscribers that went away are dropped on the next publish.
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (event_tx, event_rx) = channel();
+
+        self.subscribers.lock().unwrap().push(event_tx);
+
+        event_rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        debug!("Event: {:?}", event);
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Response body of `GET /events` streaming events in the `text/event-stream`
+/// format until the client disconnects
+pub struct EventStream {
+    event_rx: Receiver<Event>,
+}
+
+impl EventStream {
+    pub fn new(bus: &EventBus) -> Self {
+        EventStream {
+            event_rx: bus.subscribe(),
+        }
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        res.write_all(b"retry: 3000\n\n")?;
+        res.flush()?;
+
+        loop {
+            match self
+                .event_rx
+                .recv_timeout(Duration::from_secs(KEEPALIVE_INTERVAL_SECS))
+            {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event)?;
+                    write!(res, "event: {}\ndata: {}\n\n", event.name(), data)?;
+                }
+                Err(RecvTimeoutError::Timeout) => res.write_all(b": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            res.flush()?;
+        }
+    }
+}
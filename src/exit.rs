@@ -1,8 +1,9 @@
 use std::sync::mpsc::Sender;
 
-use nix::sys::signal::{SigSet, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use nix::sys::signal::{SigSet, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
 
 use errors::*;
+use logger::step_log_level;
 
 pub type ExitResult = Result<()>;
 
@@ -17,15 +18,28 @@ pub fn block_exit_signals() -> Result<()> {
         .chain_err(|| ErrorKind::BlockExitSignals)
 }
 
-/// Trap exit signals from a signal handling thread
-pub fn trap_exit_signals() -> Result<()> {
+/// Trap exit signals from a signal handling thread. Meanwhile `SIGHUP`
+/// reloads the configuration, and `SIGUSR1` and `SIGUSR2` step the log level
+/// up and down.
+pub fn trap_exit_signals<F>(mut reload: F) -> Result<()>
+where
+    F: FnMut(),
+{
     let mask = create_exit_sigmask();
 
-    let sig = mask.wait().chain_err(|| ErrorKind::TrapExitSignals)?;
-
-    info!("\nReceived {:?}", sig);
-
-    Ok(())
+    loop {
+        let sig = mask.wait().chain_err(|| ErrorKind::TrapExitSignals)?;
+
+        match sig {
+            SIGHUP => reload(),
+            SIGUSR1 => step_log_level(true),
+            SIGUSR2 => step_log_level(false),
+            _ => {
+                info!("\nReceived {:?}", sig);
+                return Ok(());
+            }
+        }
+    }
 }
 
 fn create_exit_sigmask() -> SigSet {
@@ -35,6 +49,8 @@ fn create_exit_sigmask() -> SigSet {
     mask.add(SIGQUIT);
     mask.add(SIGTERM);
     mask.add(SIGHUP);
+    mask.add(SIGUSR1);
+    mask.add(SIGUSR2);
 
     mask
 }
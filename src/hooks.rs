@@ -0,0 +1,195 @@
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::header::ContentType;
+use hyper::{Client, Url};
+use serde_json::{self, Value};
+
+use config::HooksConfig;
+use errors::*;
+use events::{Event, EventBus};
+
+/// Hooks are run one at a time. A hanging script or webhook would otherwise
+/// delay every later hook and the exit of WiFi Connect.
+const HOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Events firing hooks. Progress events like scan results are only streamed.
+const HOOK_EVENTS: &[&str] = &[
+    "portal_started",
+    "activated",
+    "connect_started",
+    "connected",
+    "connect_failed",
+    "timeout",
+    "exit",
+];
+
+const ENV_PREFIX: &str = "WIFI_CONNECT_";
+
+/// Runs the configured script and webhook on events from the network thread
+pub struct Hooks {
+    handle: thread::JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Hooks {
+    /// Stops running hooks, e.g. when replaced after a configuration reload.
+    /// A hook already running is not interrupted.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for the hooks of the `exit` event to finish
+    pub fn wait(self) {
+        if self.handle.join().is_err() {
+            error!("Hooks thread panicked");
+        }
+    }
+}
+
+pub fn start_hooks(config: &HooksConfig, events: &EventBus) -> Result<Option<Hooks>> {
+    if config.script.is_none() && config.url.is_none() {
+        return Ok(None);
+    }
+
+    let url = match config.url {
+        Some(ref url) => Some(Url::parse(url).chain_err(|| ErrorKind::HookUrl(url.clone()))?),
+        None => None,
+    };
+
+    let script = config.script.clone();
+
+    let event_rx = events.subscribe();
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopped_thread = stopped.clone();
+
+    let handle = thread::spawn(move || {
+        for event in event_rx.iter() {
+            if stopped_thread.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let name = event.name();
+
+            if HOOK_EVENTS.contains(&name) {
+                if let Some(ref script) = script {
+                    run_script(script.as_os_str(), &event);
+                }
+
+                if let Some(ref url) = url {
+                    post_webhook(url, &event);
+                }
+            }
+
+            if let Event::Exit = event {
+                return;
+            }
+        }
+    });
+
+    Ok(Some(Hooks { handle, stopped }))
+}
+
+/// The event name is passed in `WIFI_CONNECT_EVENT` and its fields in
+/// `WIFI_CONNECT_<FIELD>` variables, e.g. `WIFI_CONNECT_SSID`
+fn run_script(script: &::std::ffi::OsStr, event: &Event) {
+    let mut command = Command::new(script);
+
+    command.env(format!("{}EVENT", ENV_PREFIX), event.name());
+
+    if let Ok(Value::Object(fields)) = serde_json::to_value(event) {
+        for (key, value) in fields {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => continue,
+            };
+
+            command.env(format!("{}{}", ENV_PREFIX, key.to_uppercase()), value);
+        }
+    }
+
+    debug!("Running hook script for {}", event.name());
+
+    match command.spawn() {
+        Ok(child) => wait_for_script(child),
+        Err(e) => warn!("Running hook script {:?} failed: {}", script, e),
+    }
+}
+
+fn wait_for_script(mut child: Child) {
+    let started = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    warn!("Hook script exited with {}", status);
+                }
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Waiting for hook script failed: {}", e);
+                return;
+            }
+        }
+
+        if started.elapsed() >= Duration::from_secs(HOOK_TIMEOUT_SECS) {
+            warn!("Hook script timed out");
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The event is sent as the JSON streamed by `GET /events`
+fn post_webhook(url: &Url, event: &Event) {
+    let body = match serde_json::to_string(event) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Serializing hook event failed: {}", e);
+            return;
+        }
+    };
+
+    debug!("Posting {} to hook URL {}", event.name(), url);
+
+    // Connecting has no timeout of its own, so the request runs on a separate
+    // thread that is left behind if the host does not answer in time.
+    let (result_tx, result_rx) = mpsc::channel();
+    let request_url = url.clone();
+
+    thread::spawn(move || {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(HOOK_TIMEOUT_SECS)));
+        client.set_write_timeout(Some(Duration::from_secs(HOOK_TIMEOUT_SECS)));
+
+        let result = client
+            .post(request_url)
+            .header(ContentType::json())
+            .body(&body[..])
+            .send()
+            .map(|response| response.status);
+
+        let _ = result_tx.send(result);
+    });
+
+    match result_rx.recv_timeout(Duration::from_secs(HOOK_TIMEOUT_SECS)) {
+        Ok(Ok(status)) => {
+            if !status.is_success() {
+                warn!("Hook URL {} replied with {}", url, status);
+            }
+        }
+        Ok(Err(e)) => warn!("Posting to hook URL {} failed: {}", url, e),
+        Err(_) => warn!("Posting to hook URL {} timed out", url),
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::process::Command;
+
+use dbus::{BusType, Connection};
+use network_manager::{NetworkManager, ServiceState};
+
+use config::{Config, InitSystem};
+use errors::*;
+use nm_dbus::{is_network_manager_running, wait_for_network_manager};
+
+/// Starts NetworkManager with the init system if it is not running yet and
+/// waits for it to appear on D-Bus
+pub fn start_network_manager(config: &Config) -> Result<()> {
+    let init_system = match config.init_system {
+        InitSystem::Auto => detect_init_system(),
+        init_system => init_system,
+    };
+
+    debug!("Init system: {:?}", init_system);
+
+    match init_system {
+        InitSystem::Systemd => start_systemd_service()?,
+        InitSystem::OpenRc => {
+            start_service("rc-service", &[&config.network_manager_service, "start"])
+        }
+        InitSystem::Runit => start_service("sv", &["up", &config.network_manager_service]),
+        InitSystem::S6 => {
+            start_service("s6-rc", &["-u", "change", &config.network_manager_service])
+        }
+        // Nothing detected, e.g. in a balena container, where the host systemd
+        // is reachable over D-Bus only
+        InitSystem::Auto => {
+            if let Err(e) = start_systemd_service() {
+                warn!("Starting NetworkManager with systemd failed: {}", e);
+            }
+        }
+        InitSystem::None => {}
+    }
+
+    wait_for_network_manager(config.network_manager_timeout)
+}
+
+fn detect_init_system() -> InitSystem {
+    if Path::new("/run/systemd/system").is_dir() {
+        InitSystem::Systemd
+    } else if Path::new("/run/openrc").is_dir() {
+        InitSystem::OpenRc
+    } else if Path::new("/run/s6-rc").exists() {
+        InitSystem::S6
+    } else if Path::new("/run/runit").is_dir() || Path::new("/etc/runit").is_dir() {
+        InitSystem::Runit
+    } else {
+        InitSystem::Auto
+    }
+}
+
+fn start_systemd_service() -> Result<()> {
+    let state = match NetworkManager::get_service_state() {
+        Ok(state) => state,
+        _ => {
+            info!("Cannot get the NetworkManager service state");
+            return Ok(());
+        }
+    };
+
+    if state != ServiceState::Active {
+        let state =
+            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
+        if state != ServiceState::Active {
+            bail!(ErrorKind::StartActiveNetworkManager);
+        } else {
+            info!("NetworkManager service started successfully");
+        }
+    } else {
+        debug!("NetworkManager service already running");
+    }
+
+    Ok(())
+}
+
+/// Failures are only logged, as waiting for NetworkManager reports whether it
+/// is running eventually
+fn start_service(program: &str, args: &[&str]) {
+    let running = Connection::get_private(BusType::System)
+        .map(|connection| is_network_manager_running(&connection))
+        .unwrap_or(false);
+
+    if running {
+        debug!("NetworkManager service already running");
+        return;
+    }
+
+    info!("Starting NetworkManager with {}...", program);
+
+    match Command::new(program).args(args).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!(
+            "Starting NetworkManager with {} exited with {}",
+            program, status
+        ),
+        Err(e) => warn!("Starting NetworkManager with {} failed: {}", program, e),
+    }
+}
@@ -1,30 +1,660 @@
-use env_logger::LogBuilder;
-use log::{LogLevel, LogLevelFilter, LogRecord};
-use std::env;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
 
-pub fn init() {
-    let mut builder = LogBuilder::new();
+use chrono::{SecondsFormat, Utc};
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use serde_json::{self, Map, Value};
 
-    if env::var("RUST_LOG").is_ok() {
-        builder.parse(&env::var("RUST_LOG").unwrap());
-    } else {
-        let format = |record: &LogRecord| {
-            if record.level() == LogLevel::Info {
-                format!("{}", record.args())
-            } else {
-                format!(
-                    "[{}:{}] {}",
-                    record.location().module_path(),
-                    record.level(),
-                    record.args()
-                )
+use config::{LogConfig, LogFormat};
+use errors::*;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const SYSLOG_IDENTIFIER: &str = "wifi-connect";
+
+/// Fields describing what WiFi Connect is currently doing, e.g. `interface`,
+/// `phase` and `ssid`. They are attached to every JSON and journald record.
+static CONTEXT: Mutex<BTreeMap<&'static str, String>> = Mutex::new(BTreeMap::new());
+
+/// Filter directives, which may be changed at runtime over the control socket
+/// and with `SIGUSR1` and `SIGUSR2`
+static DIRECTIVES: RwLock<Vec<Directive>> = RwLock::new(Vec::new());
+
+static MAX_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+
+/// The most recent records, kept for `GET /logs` and the diagnostics bundle
+static RECENT: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Serialize)]
+pub struct LogEntry {
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+pub fn set_context(key: &'static str, value: &str) {
+    CONTEXT.lock().unwrap().insert(key, value.to_string());
+}
+
+pub fn clear_context(key: &'static str) {
+    CONTEXT.lock().unwrap().remove(key);
+}
+
+/// Filter directive like `info` or `iron::iron=off` as in `RUST_LOG`
+struct Directive {
+    target: Option<String>,
+    level: LogLevelFilter,
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    format: LogFormat,
+    output: Mutex<Output>,
+    buffer_size: usize,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        is_enabled(
+            &DIRECTIVES.read().unwrap(),
+            metadata.target(),
+            metadata.level(),
+        )
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        self.buffer(record);
+
+        let mut output = self.output.lock().unwrap();
+
+        let result = match *output {
+            Output::Journald(ref socket) => socket
+                .send_to(&format_journald(record), JOURNALD_SOCKET)
+                .map(|_| ()),
+            Output::Stderr => {
+                let line = self.format_line(record);
+                io::stderr().write_all(line.as_bytes())
+            }
+            Output::File(ref mut file) => {
+                let line = self.format_line(record);
+                file.write(line.as_bytes())
             }
         };
 
-        builder.format(format).filter(None, LogLevelFilter::Info);
+        if let Err(e) = result {
+            let _ = writeln!(io::stderr(), "Writing log record failed: {}", e);
+        }
+    }
+}
+
+impl Logger {
+    fn buffer(&self, record: &LogRecord) {
+        if self.buffer_size == 0 {
+            return;
+        }
+
+        let mut recent = RECENT.lock().unwrap();
+
+        while recent.len() >= self.buffer_size {
+            recent.pop_front();
+        }
+
+        recent.push_back(LogEntry {
+            timestamp: timestamp(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn format_line(&self, record: &LogRecord) -> String {
+        match self.format {
+            LogFormat::Json => format_json(record),
+            _ => format_text(record),
+        }
+    }
+}
+
+pub fn init(config: &LogConfig) -> Result<()> {
+    *DIRECTIVES.write().unwrap() = parse_directives(&config.filter);
+
+    let output = match (config.format, &config.file) {
+        (LogFormat::Journald, _) => Output::Journald(UnixDatagram::unbound()?),
+        (_, Some(path)) => Output::File(
+            RotatingFile::open(path, config.file_max_size, config.file_count)
+                .chain_err(|| ErrorKind::LogFile(path.display().to_string()))?,
+        ),
+        (_, None) => Output::Stderr,
+    };
+
+    let logger = Logger {
+        format: config.format,
+        output: Mutex::new(output),
+        buffer_size: config.buffer_size,
+    };
+
+    log::set_logger(|max_level: MaxLogLevelFilter| {
+        max_level.set(max_directive_level(&DIRECTIVES.read().unwrap()));
+        *MAX_LEVEL.lock().unwrap() = Some(max_level);
+        Box::new(logger)
+    })
+    .expect("Logger already initialized");
+
+    Ok(())
+}
+
+/// Replaces the filter, e.g. when the configuration is reloaded
+pub fn set_filter(filter: &str) {
+    let mut directives = DIRECTIVES.write().unwrap();
+
+    *directives = parse_directives(filter);
 
-        builder.parse("wifi-connect=info,iron::iron=off");
+    update_max_level(&directives);
+}
+
+/// Sets the level of a module path like `wifi_connect::network`, or the
+/// default level without one
+pub fn set_log_level(target: Option<&str>, level: LogLevelFilter) {
+    let mut directives = DIRECTIVES.write().unwrap();
+
+    let target = target.map(String::from);
+
+    match directives
+        .iter_mut()
+        .find(|directive| directive.target == target)
+    {
+        Some(directive) => directive.level = level,
+        None => directives.push(Directive { target, level }),
+    }
+
+    update_max_level(&directives);
+}
+
+/// Steps the default level up or down by one, between `error` and `trace`,
+/// keeping module specific levels
+pub fn step_log_level(more_verbose: bool) {
+    let level = {
+        let mut directives = DIRECTIVES.write().unwrap();
+
+        if !directives
+            .iter()
+            .any(|directive| directive.target.is_none())
+        {
+            directives.push(Directive {
+                target: None,
+                level: LogLevelFilter::Info,
+            });
+        }
+
+        let directive = directives
+            .iter_mut()
+            .find(|directive| directive.target.is_none())
+            .unwrap();
+
+        directive.level = step_level(directive.level, more_verbose);
+
+        let level = directive.level;
+
+        update_max_level(&directives);
+
+        level
+    };
+
+    warn!("Log level set to {}", level.to_string().to_lowercase());
+}
+
+/// Current filter in the `RUST_LOG` syntax, e.g. `info,iron::iron=off`
+pub fn log_levels() -> String {
+    DIRECTIVES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|directive| {
+            let level = directive.level.to_string().to_lowercase();
+
+            match directive.target {
+                Some(ref target) => format!("{}={}", target, level),
+                None => level,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn update_max_level(directives: &[Directive]) {
+    if let Some(ref max_level) = *MAX_LEVEL.lock().unwrap() {
+        max_level.set(max_directive_level(directives));
+    }
+}
+
+fn step_level(level: LogLevelFilter, more_verbose: bool) -> LogLevelFilter {
+    match (level, more_verbose) {
+        (LogLevelFilter::Off, true) | (LogLevelFilter::Error, true) => LogLevelFilter::Warn,
+        (LogLevelFilter::Warn, true) => LogLevelFilter::Info,
+        (LogLevelFilter::Info, true) => LogLevelFilter::Debug,
+        (LogLevelFilter::Debug, true) | (LogLevelFilter::Trace, true) => LogLevelFilter::Trace,
+        (LogLevelFilter::Trace, false) => LogLevelFilter::Debug,
+        (LogLevelFilter::Debug, false) => LogLevelFilter::Info,
+        (LogLevelFilter::Info, false) => LogLevelFilter::Warn,
+        (LogLevelFilter::Warn, false)
+        | (LogLevelFilter::Error, false)
+        | (LogLevelFilter::Off, false) => LogLevelFilter::Error,
+    }
+}
+
+/// Returns the buffered records, oldest first
+pub fn recent_logs() -> Vec<LogEntry> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+/// Logs the error ending WiFi Connect with its kind as a structured field.
+/// Plain text output already has the error printed to stderr by `main`.
+pub fn log_exit_error(format: LogFormat, e: &Error) {
+    if let LogFormat::Text = format {
+        return;
+    }
+
+    let kind = format!("{:?}", e.kind());
+    let kind = kind.split(['(', ' ']).next().unwrap_or("");
+    set_context("error_kind", kind);
+
+    let message = e
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ");
+
+    error!("{}", message);
+}
+
+fn parse_directives(spec: &str) -> Vec<Directive> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let first = parts.next().unwrap();
+
+            let (target, level) = match parts.next() {
+                Some(level) => (Some(first.to_string()), level),
+                None => match first.parse::<LogLevelFilter>() {
+                    Ok(level) => {
+                        return Some(Directive {
+                            target: None,
+                            level,
+                        })
+                    }
+                    Err(_) => (Some(first.to_string()), "trace"),
+                },
+            };
+
+            match level.parse() {
+                Ok(level) => Some(Directive { target, level }),
+                Err(_) => {
+                    let _ = writeln!(io::stderr(), "Ignoring log directive: {}", directive);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The most specific directive matching the target applies, which is the
+/// directive with the longest target prefix
+fn is_enabled(directives: &[Directive], target: &str, level: LogLevel) -> bool {
+    let directive = directives
+        .iter()
+        .filter(|directive| match directive.target {
+            Some(ref prefix) => target.starts_with(prefix.as_str()),
+            None => true,
+        })
+        .max_by_key(|directive| directive.target.as_ref().map_or(0, String::len));
+
+    match directive {
+        Some(directive) => level <= directive.level,
+        None => false,
     }
+}
+
+fn max_directive_level(directives: &[Directive]) -> LogLevelFilter {
+    directives
+        .iter()
+        .map(|directive| directive.level)
+        .max()
+        .unwrap_or(LogLevelFilter::Off)
+}
 
-    builder.init().unwrap();
+/// Info messages are shown as is, as they are meant for the user
+fn format_text(record: &LogRecord) -> String {
+    if record.level() == LogLevel::Info {
+        format!("{}\n", record.args())
+    } else {
+        format!(
+            "[{}:{}] {}\n",
+            record.location().module_path(),
+            record.level(),
+            record.args()
+        )
+    }
+}
+
+fn format_json(record: &LogRecord) -> String {
+    let mut fields = Map::new();
+
+    fields.insert("timestamp".into(), timestamp().into());
+    fields.insert("level".into(), record.level().to_string().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+
+    for (key, value) in CONTEXT.lock().unwrap().iter() {
+        fields.insert((*key).into(), value.clone().into());
+    }
+
+    let mut line = serde_json::to_string(&Value::Object(fields)).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+pub fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Native journald protocol, so that context fields end up as journal fields
+fn format_journald(record: &LogRecord) -> Vec<u8> {
+    let mut datagram = Vec::new();
+
+    let priority = match record.level() {
+        LogLevel::Error => "3",
+        LogLevel::Warn => "4",
+        LogLevel::Info => "6",
+        LogLevel::Debug | LogLevel::Trace => "7",
+    };
+
+    append_journald_field(&mut datagram, "MESSAGE", &record.args().to_string());
+    append_journald_field(&mut datagram, "PRIORITY", priority);
+    append_journald_field(&mut datagram, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    append_journald_field(
+        &mut datagram,
+        "CODE_MODULE",
+        record.location().module_path(),
+    );
+    append_journald_field(&mut datagram, "CODE_FILE", record.location().file());
+    append_journald_field(
+        &mut datagram,
+        "CODE_LINE",
+        &record.location().line().to_string(),
+    );
+
+    for (key, value) in CONTEXT.lock().unwrap().iter() {
+        append_journald_field(&mut datagram, &key.to_uppercase(), value);
+    }
+
+    datagram
+}
+
+/// Values with newlines are length prefixed as required by the protocol
+fn append_journald_field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+    datagram.extend_from_slice(key.as_bytes());
+
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+/// Log file rotated by size to `<file>.1` ... `<file>.<count>`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    count: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, count: u32) -> io::Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            count,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.count).rev() {
+            let from = rotated_path(&self.path, index);
+
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+
+        if self.count > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn levels(directives: &[Directive]) -> Vec<(Option<&str>, LogLevelFilter)> {
+        directives
+            .iter()
+            .map(|directive| (directive.target.as_deref(), directive.level))
+            .collect()
+    }
+
+    /// A fresh directory per test, as tests run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wifi-connect-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_default_and_module_levels() {
+        assert_eq!(
+            levels(&parse_directives(
+                "info,iron::iron=off, wifi_connect::network=trace"
+            )),
+            vec![
+                (None, LogLevelFilter::Info),
+                (Some("iron::iron"), LogLevelFilter::Off),
+                (Some("wifi_connect::network"), LogLevelFilter::Trace),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_module_without_level_enables_everything() {
+        assert_eq!(
+            levels(&parse_directives("wifi_connect")),
+            vec![(Some("wifi_connect"), LogLevelFilter::Trace)]
+        );
+    }
+
+    #[test]
+    fn parse_skips_invalid_and_empty_directives() {
+        assert_eq!(
+            levels(&parse_directives("debug,,iron=loud")),
+            vec![(None, LogLevelFilter::Debug)]
+        );
+        assert!(parse_directives("").is_empty());
+    }
+
+    #[test]
+    fn most_specific_directive_applies() {
+        let directives = parse_directives("info,iron=warn,iron::iron=off");
+
+        assert!(is_enabled(&directives, "wifi_connect", LogLevel::Info));
+        assert!(!is_enabled(&directives, "wifi_connect", LogLevel::Debug));
+        assert!(is_enabled(&directives, "iron::request", LogLevel::Warn));
+        assert!(!is_enabled(&directives, "iron::request", LogLevel::Info));
+        assert!(!is_enabled(&directives, "iron::iron", LogLevel::Error));
+    }
+
+    #[test]
+    fn nothing_is_enabled_without_matching_directive() {
+        let directives = parse_directives("iron=trace");
+
+        assert!(!is_enabled(&directives, "wifi_connect", LogLevel::Error));
+        assert_eq!(max_directive_level(&directives), LogLevelFilter::Trace);
+        assert_eq!(max_directive_level(&[]), LogLevelFilter::Off);
+    }
+
+    #[test]
+    fn step_level_stays_between_error_and_trace() {
+        assert_eq!(
+            step_level(LogLevelFilter::Info, true),
+            LogLevelFilter::Debug
+        );
+        assert_eq!(
+            step_level(LogLevelFilter::Trace, true),
+            LogLevelFilter::Trace
+        );
+        assert_eq!(step_level(LogLevelFilter::Off, true), LogLevelFilter::Warn);
+        assert_eq!(
+            step_level(LogLevelFilter::Info, false),
+            LogLevelFilter::Warn
+        );
+        assert_eq!(
+            step_level(LogLevelFilter::Error, false),
+            LogLevelFilter::Error
+        );
+        assert_eq!(
+            step_level(LogLevelFilter::Off, false),
+            LogLevelFilter::Error
+        );
+    }
+
+    #[test]
+    fn journald_fields() {
+        let mut datagram = Vec::new();
+
+        append_journald_field(&mut datagram, "MESSAGE", "connected");
+        assert_eq!(datagram, b"MESSAGE=connected\n");
+
+        datagram.clear();
+        append_journald_field(&mut datagram, "MESSAGE", "a\nb");
+        assert_eq!(datagram, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+
+    #[test]
+    fn file_is_rotated_by_size() {
+        let dir = test_dir("rotate");
+        let path = dir.join("wifi-connect.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+        file.write(b"first\n").unwrap();
+        file.write(b"second\n").unwrap();
+        file.write(b"third\n").unwrap();
+        file.write(b"fourth\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_is_truncated_without_rotated_files() {
+        let dir = test_dir("truncate");
+        let path = dir.join("wifi-connect.log");
+
+        let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+
+        file.write(b"first\n").unwrap();
+        file.write(b"second\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert!(!rotated_path(&path, 1).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopened_file_keeps_its_size() {
+        let dir = test_dir("reopen");
+        let path = dir.join("wifi-connect.log");
+
+        fs::write(&path, "existing\n").unwrap();
+
+        let mut file = RotatingFile::open(&path, 10, 1).unwrap();
+        file.write(b"new\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "existing\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
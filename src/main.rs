@@ -12,62 +12,103 @@ extern crate serde_derive;
 #[macro_use]
 extern crate clap;
 
-extern crate env_logger;
+#[macro_use]
+extern crate serde_json;
+
+#[macro_use]
+extern crate schemars;
+
+extern crate caps;
+extern crate chrono;
+extern crate dbus;
+extern crate hyper;
 extern crate iron;
 extern crate iron_cors;
 extern crate mount;
 extern crate network_manager;
 extern crate nix;
+extern crate openssl;
 extern crate params;
 extern crate persistent;
 extern crate router;
-extern crate serde_json;
+extern crate sd_notify;
+extern crate serde;
 extern crate staticfile;
 
+mod access;
+mod channel;
 mod config;
+mod control;
+mod dbus_service;
+mod device;
 mod dnsmasq;
 mod errors;
+mod events;
 mod exit;
+mod hooks;
+mod init_system;
 mod logger;
+mod metrics;
 mod network;
+mod nm_dbus;
+mod openapi;
 mod privileges;
+mod radio;
+mod regulatory;
 mod server;
+mod session_lock;
+mod systemd;
+mod tls;
+mod validation;
 
 use std::io::Write;
-use std::path;
 use std::process;
 use std::sync::mpsc::channel;
 use std::thread;
 
-use config::get_config;
+use config::{get_config, Config};
 use errors::*;
 use exit::block_exit_signals;
 use network::{init_networking, process_network_commands};
-use privileges::require_root;
+use privileges::require_privileges;
 
 fn main() {
-    if let Err(ref e) = run() {
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "Error writing to stderr";
+    let config = match get_config() {
+        Ok(config) => config,
+        Err(ref e) => exit_with_error(e),
+    };
+    let log_format = config.log.format;
 
-        writeln!(stderr, "\x1B[1;31mError: {}\x1B[0m", e).expect(errmsg);
+    if let Err(ref e) = run(config) {
+        logger::log_exit_error(log_format, e);
 
-        for inner in e.iter().skip(1) {
-            writeln!(stderr, "  caused by: {}", inner).expect(errmsg);
-        }
+        exit_with_error(e);
+    }
+}
+
+fn exit_with_error(e: &Error) -> ! {
+    let stderr = &mut ::std::io::stderr();
+    let errmsg = "Error writing to stderr";
 
-        process::exit(exit_code(e));
+    writeln!(stderr, "\x1B[1;31mError: {}\x1B[0m", e).expect(errmsg);
+
+    for inner in e.iter().skip(1) {
+        writeln!(stderr, "  caused by: {}", inner).expect(errmsg);
     }
+
+    process::exit(exit_code(e));
 }
 
-fn run() -> Result<()> {
+fn run(config: Config) -> Result<()> {
     block_exit_signals()?;
 
-    logger::init();
+    logger::init(&config.log)?;
 
-    let config = get_config();
+    if config.socket_activation {
+        systemd::init_socket_activation();
+    }
 
-    require_root()?;
+    require_privileges(&config)?;
 
     init_networking(&config)?;
 
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iron::modifiers::Header;
+use iron::{headers, status, BeforeMiddleware, Handler, Iron, IronResult, Request, Response};
+use router::Router;
+
+use errors::*;
+use exit::{exit, ExitResult};
+use network::State;
+
+/// Clients which made a request within this window count as portal clients
+const PORTAL_CLIENT_WINDOW_SECS: u64 = 300;
+
+const CONNECT_DURATION_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+const SCAN_DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0];
+
+const STATES: &[State] = &[
+    State::Idle,
+    State::Portal,
+    State::Connecting,
+    State::Connected,
+];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bucket, count);
+        }
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+struct Values {
+    portal_starts: u64,
+    connect_attempts: BTreeMap<(&'static str, String), u64>,
+    connect_duration: Histogram,
+    scan_duration: Histogram,
+    dnsmasq_starts: u64,
+    portal_clients: HashMap<IpAddr, Instant>,
+    state: State,
+}
+
+/// Provisioning metrics in the Prometheus text format, shared between the
+/// network thread and the web servers
+pub struct Metrics {
+    values: Mutex<Values>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            values: Mutex::new(Values {
+                portal_starts: 0,
+                connect_attempts: BTreeMap::new(),
+                connect_duration: Histogram::new(CONNECT_DURATION_BUCKETS),
+                scan_duration: Histogram::new(SCAN_DURATION_BUCKETS),
+                dnsmasq_starts: 0,
+                portal_clients: HashMap::new(),
+                state: State::Idle,
+            }),
+        }
+    }
+
+    pub fn portal_started(&self) {
+        self.values.lock().unwrap().portal_starts += 1;
+    }
+
+    /// `outcome` is one of `success`, `invalid_credentials`, `not_found`,
+    /// `not_activated` or `error`
+    pub fn connect_attempt(&self, outcome: &'static str, security: &str) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .connect_attempts
+            .entry((outcome, security.to_string()))
+            .or_insert(0) += 1;
+    }
+
+    pub fn connected(&self, duration: Duration) {
+        self.values
+            .lock()
+            .unwrap()
+            .connect_duration
+            .observe(as_secs(duration));
+    }
+
+    pub fn scanned(&self, duration: Duration) {
+        self.values
+            .lock()
+            .unwrap()
+            .scan_duration
+            .observe(as_secs(duration));
+    }
+
+    pub fn dnsmasq_started(&self) {
+        self.values.lock().unwrap().dnsmasq_starts += 1;
+    }
+
+    pub fn client_seen(&self, client: IpAddr) {
+        self.values
+            .lock()
+            .unwrap()
+            .portal_clients
+            .insert(client, Instant::now());
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.values.lock().unwrap().state = state;
+    }
+
+    fn render(&self) -> String {
+        let mut values = self.values.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "wifi_connect_portal_starts_total",
+            "Number of times the captive portal was started",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "wifi_connect_portal_starts_total {}",
+            values.portal_starts
+        );
+
+        header(
+            &mut out,
+            "wifi_connect_connect_attempts_total",
+            "Number of connect attempts by outcome and security type",
+            "counter",
+        );
+        for (&(outcome, ref security), count) in &values.connect_attempts {
+            let _ = writeln!(
+                out,
+                "wifi_connect_connect_attempts_total{{outcome=\"{}\",security=\"{}\"}} {}",
+                outcome, security, count
+            );
+        }
+
+        values.connect_duration.render(
+            &mut out,
+            "wifi_connect_connect_duration_seconds",
+            "Time from accepting credentials to an activated connection",
+        );
+
+        values.scan_duration.render(
+            &mut out,
+            "wifi_connect_scan_duration_seconds",
+            "Time to get the list of access points",
+        );
+
+        // The first start is not a restart
+        header(
+            &mut out,
+            "wifi_connect_dnsmasq_restarts_total",
+            "Number of times dnsmasq was started again",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "wifi_connect_dnsmasq_restarts_total {}",
+            values.dnsmasq_starts.saturating_sub(1)
+        );
+
+        let window = Duration::from_secs(PORTAL_CLIENT_WINDOW_SECS);
+        values
+            .portal_clients
+            .retain(|_, last_seen| last_seen.elapsed() < window);
+
+        header(
+            &mut out,
+            "wifi_connect_portal_clients",
+            "Number of clients which used the captive portal in the last 5 minutes",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "wifi_connect_portal_clients {}",
+            values.portal_clients.len()
+        );
+
+        header(
+            &mut out,
+            "wifi_connect_state",
+            "Current state, 1 for the active one",
+            "gauge",
+        );
+        for state in STATES {
+            let _ = writeln!(
+                out,
+                "wifi_connect_state{{state=\"{}\"}} {}",
+                state.as_str(),
+                if *state == values.state { 1 } else { 0 }
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Serves `GET /metrics`
+pub struct MetricsHandler {
+    pub metrics: Arc<Metrics>,
+}
+
+impl Handler for MetricsHandler {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let content_type = "text/plain; version=0.0.4".parse().unwrap();
+
+        Ok(Response::with((
+            status::Ok,
+            self.metrics.render(),
+            Header(headers::ContentType(content_type)),
+        )))
+    }
+}
+
+/// Counts the clients using the captive portal
+pub struct TrackClients {
+    pub metrics: Arc<Metrics>,
+}
+
+impl BeforeMiddleware for TrackClients {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        self.metrics.client_seen(req.remote_addr.ip());
+        Ok(())
+    }
+}
+
+/// Metrics are served on the loopback interface only, independently of the
+/// captive portal
+pub fn start_metrics_server(port: u16, metrics: &Arc<Metrics>, exit_tx: &Sender<ExitResult>) {
+    let mut router = Router::new();
+    router.get(
+        "/metrics",
+        MetricsHandler {
+            metrics: metrics.clone(),
+        },
+        "metrics",
+    );
+    let exit_tx = exit_tx.clone();
+
+    thread::spawn(move || {
+        let address = format!("127.0.0.1:{}", port);
+
+        info!("Starting metrics server on {}", &address);
+
+        if let Err(e) = Iron::new(router).http(&address) {
+            exit(
+                &exit_tx,
+                ErrorKind::StartHTTPServer(address, e.to_string()).into(),
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(metrics: &Metrics, name: &str) -> Vec<String> {
+        metrics
+            .render()
+            .lines()
+            .filter(|line| line.starts_with(name))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "duration_seconds", "Duration");
+
+        assert_eq!(
+            out,
+            "# HELP duration_seconds Duration
+# TYPE duration_seconds histogram
+duration_seconds_bucket{le=\"1\"} 1
+duration_seconds_bucket{le=\"5\"} 2
+duration_seconds_bucket{le=\"+Inf\"} 3
+duration_seconds_sum 12.5
+duration_seconds_count 3
+"
+        );
+    }
+
+    #[test]
+    fn connect_attempts_by_outcome_and_security() {
+        let metrics = Metrics::new();
+        metrics.connect_attempt("success", "wpa");
+        metrics.connect_attempt("success", "wpa");
+        metrics.connect_attempt("invalid_credentials", "wpa");
+
+        assert_eq!(
+            lines(&metrics, "wifi_connect_connect_attempts_total{"),
+            vec![
+                "wifi_connect_connect_attempts_total{outcome=\"invalid_credentials\",security=\"wpa\"} 1",
+                "wifi_connect_connect_attempts_total{outcome=\"success\",security=\"wpa\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn first_dnsmasq_start_is_not_a_restart() {
+        let metrics = Metrics::new();
+
+        assert_eq!(
+            lines(&metrics, "wifi_connect_dnsmasq_restarts_total "),
+            vec!["wifi_connect_dnsmasq_restarts_total 0"]
+        );
+
+        metrics.dnsmasq_started();
+        metrics.dnsmasq_started();
+
+        assert_eq!(
+            lines(&metrics, "wifi_connect_dnsmasq_restarts_total "),
+            vec!["wifi_connect_dnsmasq_restarts_total 1"]
+        );
+    }
+
+    #[test]
+    fn portal_clients_are_counted_once() {
+        let metrics = Metrics::new();
+        metrics.client_seen(IpAddr::from([192, 168, 42, 2]));
+        metrics.client_seen(IpAddr::from([192, 168, 42, 2]));
+        metrics.client_seen(IpAddr::from([192, 168, 42, 3]));
+
+        assert_eq!(
+            lines(&metrics, "wifi_connect_portal_clients "),
+            vec!["wifi_connect_portal_clients 2"]
+        );
+    }
+
+    #[test]
+    fn only_current_state_is_set() {
+        let metrics = Metrics::new();
+        metrics.set_state(State::Portal);
+
+        assert_eq!(
+            lines(&metrics, "wifi_connect_state{"),
+            vec![
+                "wifi_connect_state{state=\"idle\"} 0",
+                "wifi_connect_state{state=\"portal\"} 1",
+                "wifi_connect_state{state=\"connecting\"} 0",
+                "wifi_connect_state{state=\"connected\"} 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let out = Metrics::new().render();
+
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let name = name
+                .trim_end_matches("_bucket")
+                .trim_end_matches("_sum")
+                .trim_end_matches("_count");
+
+            assert!(out.contains(&format!("# TYPE {} ", name)), "{}", line);
+        }
+    }
+}
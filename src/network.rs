@@ -1,52 +1,304 @@
 use std::collections::HashSet;
+use std::mem;
 use std::net::Ipv4Addr;
 use std::process;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use network_manager::{
     AccessPoint, AccessPointCredentials, Connection, ConnectionState, Connectivity, Device,
-    DeviceState, DeviceType, NetworkManager, Security, ServiceState,
+    DeviceState, DeviceType, NetworkManager, Security,
 };
 
-use config::Config;
+use access::init_access_code;
+use channel::portal_channel;
+use config::{get_config, Config, PortalBand, SharedConfig};
+use control::{start_control_server, stop_control_server};
+use dbus_service::start_dbus_service;
+use device::{device_capabilities, driver, mac_address, DeviceCapabilities, DeviceSelector};
 use dnsmasq::{start_dnsmasq, stop_dnsmasq};
 use errors::*;
+use events::{Event, EventBus};
 use exit::{exit, trap_exit_signals, ExitResult};
+use hooks::{start_hooks, Hooks};
+use init_system::start_network_manager;
+use logger;
+use metrics::{start_metrics_server, Metrics};
+use nm_dbus::{
+    add_hotspot_connection, set_device_managed, spawn_network_manager_watcher,
+    wait_for_network_manager, HotspotSettings,
+};
+use radio::check_radio;
+use regulatory::{init_regulatory_domain, regulatory_domain};
 use server::start_server;
+use systemd::{self, notify_status, watchdog_idle, watchdog_progress};
+use validation::{validate_credentials, ValidationError};
+
+/// Commands originating from HTTP requests carry their own reply channel, so
+/// that responses cannot be mismatched between concurrent requests
+pub struct ReplyTx {
+    tx: Sender<NetworkCommandResponse>,
+    requester: Weak<()>,
+}
+
+/// Requesters give up waiting after a timeout and drop their end. Commands
+/// received afterwards are skipped, so that e.g. a connection attempt the
+/// client was told timed out does not run later.
+pub struct ReplyRx {
+    rx: Receiver<NetworkCommandResponse>,
+    _requester: Arc<()>,
+}
+
+pub fn reply_channel() -> (ReplyTx, ReplyRx) {
+    let (tx, rx) = channel();
+    let requester = Arc::new(());
+
+    let reply_tx = ReplyTx {
+        tx,
+        requester: Arc::downgrade(&requester),
+    };
+
+    let reply_rx = ReplyRx {
+        rx,
+        _requester: requester,
+    };
+
+    (reply_tx, reply_rx)
+}
+
+impl ReplyTx {
+    fn is_requester_gone(&self) -> bool {
+        self.requester.upgrade().is_none()
+    }
+}
+
+impl ReplyRx {
+    pub fn recv(&self) -> ::std::result::Result<NetworkCommandResponse, RecvError> {
+        self.rx.recv()
+    }
+
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> ::std::result::Result<NetworkCommandResponse, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+/// Clients get a warning before the activity timeout shuts the portal down
+const TIMEOUT_WARNING_SECS: u64 = 30;
+
+/// Delay between accepting credentials and stopping the portal, so that the
+/// client receives the response and the `portal_stopping` event
+const PORTAL_STOP_DELAY_SECS: u64 = 3;
+
+/// Attempts at recovering from a NetworkManager restart before giving up
+const NETWORK_MANAGER_RECOVERY_ATTEMPTS: u32 = 5;
+const NETWORK_MANAGER_RECOVERY_DELAY_SECS: u64 = 2;
+
+/// A device may be unmanaged for a moment after being added
+const DEVICE_ADDED_TIMEOUT_SECS: u64 = 10;
+
+const DEVICE_POLL_INTERVAL_SECS: u64 = 1;
+
+const MANAGE_DEVICE_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DeviceRole {
+    Portal,
+    Target,
+}
 
 pub enum NetworkCommand {
-    Activate,
-    Timeout,
+    Activate {
+        reply_tx: ReplyTx,
+    },
+    /// Timeouts carry the portal session they were started for, so that a
+    /// timeout of a portal stopped in the meantime is ignored
+    TimeoutWarning {
+        portal_session: u64,
+    },
+    Timeout {
+        portal_session: u64,
+    },
     Exit,
+    Reload,
+    NetworkManagerRestarted,
+    DeviceAdded,
+    DeviceRemoved,
     Connect {
         ssid: String,
         identity: String,
         passphrase: String,
+        reply_tx: ReplyTx,
+    },
+    Status {
+        reply_tx: ReplyTx,
+    },
+    Device {
+        reply_tx: ReplyTx,
+    },
+    StartPortal {
+        reply_tx: ReplyTx,
+    },
+    StopPortal {
+        reply_tx: ReplyTx,
+    },
+    SavedNetworks {
+        reply_tx: ReplyTx,
+    },
+    ForgetNetwork {
+        ssid: String,
+        reply_tx: ReplyTx,
+    },
+    Rescan {
+        reply_tx: ReplyTx,
+    },
+    Diagnostics {
+        reply_tx: ReplyTx,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+impl NetworkCommand {
+    fn reply_tx(&self) -> Option<&ReplyTx> {
+        match *self {
+            NetworkCommand::Activate { ref reply_tx }
+            | NetworkCommand::Connect { ref reply_tx, .. }
+            | NetworkCommand::Status { ref reply_tx }
+            | NetworkCommand::Device { ref reply_tx }
+            | NetworkCommand::StartPortal { ref reply_tx }
+            | NetworkCommand::StopPortal { ref reply_tx }
+            | NetworkCommand::SavedNetworks { ref reply_tx }
+            | NetworkCommand::ForgetNetwork { ref reply_tx, .. }
+            | NetworkCommand::Rescan { ref reply_tx }
+            | NetworkCommand::Diagnostics { ref reply_tx } => Some(reply_tx),
+            NetworkCommand::TimeoutWarning { .. }
+            | NetworkCommand::Timeout { .. }
+            | NetworkCommand::Exit
+            | NetworkCommand::Reload
+            | NetworkCommand::NetworkManagerRestarted
+            | NetworkCommand::DeviceAdded
+            | NetworkCommand::DeviceRemoved => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct Network {
     ssid: String,
     security: String,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct SavedNetwork {
+    ssid: String,
+    id: String,
+    uuid: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Idle,
+    Portal,
+    Connecting,
+    Connected,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            State::Idle => "idle",
+            State::Portal => "portal",
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ConnectionAttempt {
+    pub ssid: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Status {
+    pub state: State,
+    pub portal_ssid: String,
+    pub activated: bool,
+    pub last_connection_attempt: Option<ConnectionAttempt>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct DeviceInfo {
+    pub interface: String,
+    pub state: String,
+    pub mac_address: Option<String>,
+    pub driver: Option<String>,
+    pub capabilities: DeviceCapabilities,
+    pub target_interface: Option<String>,
+    pub target_removed: bool,
+    pub regulatory_domain: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetworkManagerInfo {
+    pub state: String,
+    pub connectivity: String,
+    pub networking_enabled: Option<bool>,
+    pub wireless_enabled: Option<bool>,
+}
+
+/// Network side of the diagnostics bundle
+#[derive(Debug, Serialize)]
+pub struct NetworkDiagnostics {
+    pub status: Status,
+    pub device: DeviceInfo,
+    pub network_manager: NetworkManagerInfo,
+    pub networks: Vec<Network>,
+    pub saved_networks: Vec<SavedNetwork>,
+}
+
 pub enum NetworkCommandResponse {
     Networks(Vec<Network>),
+    Connecting,
+    InvalidCredentials(ValidationError),
+    Status(Status),
+    Device(DeviceInfo),
+    SavedNetworks(Vec<SavedNetwork>),
+    Diagnostics(NetworkDiagnostics),
+    Done,
+    Failed(String),
 }
 
 struct NetworkCommandHandler {
     manager: NetworkManager,
     device: Device,
+    target_device: Option<Device>,
     access_points: Vec<AccessPoint>,
     portal_connection: Option<Connection>,
     config: Config,
-    dnsmasq: process::Child,
-    server_tx: Sender<NetworkCommandResponse>,
+    shared_config: SharedConfig,
+    dnsmasq: Option<process::Child>,
+    network_tx: Sender<NetworkCommand>,
     network_rx: Receiver<NetworkCommand>,
+    exit_tx: Sender<ExitResult>,
     activated: bool,
+    state: State,
+    last_connection_attempt: Option<ConnectionAttempt>,
+    events: EventBus,
+    hooks: Option<Hooks>,
+    metrics: Arc<Metrics>,
+    access_code: Option<String>,
+    server_address: Option<(Ipv4Addr, u16)>,
+    portal_session: u64,
+    device_removed: bool,
+    target_removed: bool,
+    portal_paused: bool,
 }
 
 impl NetworkCommandHandler {
@@ -58,70 +310,135 @@ impl NetworkCommandHandler {
         let manager = NetworkManager::new();
         debug!("NetworkManager connection initialized");
 
-        let device = find_device(&manager, &config.interface)?;
+        let (device, target_device) = wait_for_devices(&manager, config, config.device_timeout)?;
 
-        let access_points = get_access_points(&device)?;
+        let mut interfaces = vec![device.interface()];
+        interfaces.extend(target_device.iter().map(Device::interface));
+        check_radio(config, &interfaces)?;
 
-        let portal_connection = Some(create_portal(&device, config)?);
+        logger::set_context("interface", device.interface());
 
-        let dnsmasq = start_dnsmasq(config, &device)?;
+        let metrics = Arc::new(Metrics::new());
 
-        let (server_tx, server_rx) = channel();
+        if let Some(port) = config.metrics_port {
+            start_metrics_server(port, &metrics, exit_tx);
+        }
+
+        let started = Instant::now();
+        let access_points = get_access_points(target_device.as_ref().unwrap_or(&device))?;
+        metrics.scanned(started.elapsed());
 
-        Self::spawn_server(config, exit_tx, server_rx, network_tx.clone());
+        let access_code = init_access_code(&config.access_code)?;
 
-        Self::spawn_activity_timeout(config, network_tx);
+        start_control_server(&config.control_socket, network_tx.clone())?;
 
-        let config = config.clone();
-        let activated = false;
+        let events = EventBus::new();
 
-        Ok(NetworkCommandHandler {
+        let hooks = start_hooks(&config.hooks, &events)?;
+
+        if let Some(bus) = config.dbus {
+            start_dbus_service(bus, &config.ssid, network_tx.clone(), &events)?;
+        }
+
+        let mut handler = NetworkCommandHandler {
             manager,
             device,
+            target_device,
             access_points,
-            portal_connection,
-            config,
-            dnsmasq,
-            server_tx,
+            portal_connection: None,
+            config: config.clone(),
+            shared_config: Arc::new(RwLock::new(config.clone())),
+            dnsmasq: None,
+            network_tx,
             network_rx,
-            activated,
-        })
+            exit_tx: exit_tx.clone(),
+            activated: false,
+            state: State::Idle,
+            last_connection_attempt: None,
+            events,
+            hooks,
+            metrics,
+            access_code,
+            server_address: None,
+            portal_session: 0,
+            device_removed: false,
+            target_removed: false,
+            portal_paused: false,
+        };
+
+        spawn_network_manager_watcher(handler.network_tx.clone());
+
+        systemd::start_watchdog();
+
+        if config.daemon {
+            info!("Running as a daemon. Waiting for control commands...");
+            systemd::notify_ready();
+        } else {
+            handler.start_portal()?;
+        }
+
+        Ok(handler)
     }
 
-    fn spawn_server(
-        config: &Config,
-        exit_tx: &Sender<ExitResult>,
-        server_rx: Receiver<NetworkCommandResponse>,
-        network_tx: Sender<NetworkCommand>,
-    ) {
-        let gateway = config.gateway;
-        let listening_port = config.listening_port;
-        let exit_tx_server = exit_tx.clone();
-        let ui_directory = config.ui_directory.clone();
+    /// The web server binds to the gateway address, which exists only after
+    /// the portal is started for the first time. It keeps running afterwards,
+    /// so another one is started only if a reload changed the address. The
+    /// previous server keeps listening on the old address until WiFi Connect
+    /// exits, since hyper 0.10 cannot stop a listening server.
+    fn spawn_server(&mut self) {
+        let address = (self.config.gateway, self.config.listening_port);
+
+        if self.server_address == Some(address) {
+            return;
+        }
+
+        let config = self.shared_config.clone();
+        let exit_tx_server = self.exit_tx.clone();
+        let network_tx = self.network_tx.clone();
+        let access_code = self.access_code.clone();
+        let events = self.events.clone();
+        let metrics = self.metrics.clone();
 
         thread::spawn(move || {
             start_server(
-                gateway,
-                listening_port,
-                server_rx,
+                &config,
                 network_tx,
                 exit_tx_server,
-                &ui_directory,
+                access_code,
+                events,
+                metrics,
             );
         });
+
+        self.server_address = Some(address);
     }
 
-    fn spawn_activity_timeout(config: &Config, network_tx: Sender<NetworkCommand>) {
+    fn spawn_activity_timeout(
+        config: &Config,
+        network_tx: Sender<NetworkCommand>,
+        portal_session: u64,
+    ) {
         let activity_timeout = config.activity_timeout;
 
         if activity_timeout == 0 {
             return;
         }
 
+        let warning = TIMEOUT_WARNING_SECS.min(activity_timeout);
+
         thread::spawn(move || {
-            thread::sleep(Duration::from_secs(activity_timeout));
+            thread::sleep(Duration::from_secs(activity_timeout - warning));
 
-            if let Err(err) = network_tx.send(NetworkCommand::Timeout) {
+            if let Err(err) = network_tx.send(NetworkCommand::TimeoutWarning { portal_session }) {
+                error!(
+                    "Sending NetworkCommand::TimeoutWarning failed: {}",
+                    err.to_string()
+                );
+            }
+
+            thread::sleep(Duration::from_secs(warning));
+
+            if let Err(err) = network_tx.send(NetworkCommand::Timeout { portal_session }) {
                 error!(
                     "Sending NetworkCommand::Timeout failed: {}",
                     err.to_string()
@@ -134,7 +451,13 @@ impl NetworkCommandHandler {
         let exit_tx_trap = exit_tx.clone();
 
         thread::spawn(move || {
-            if let Err(e) = trap_exit_signals() {
+            let reload = || {
+                if let Err(err) = network_tx.send(NetworkCommand::Reload) {
+                    error!("Sending NetworkCommand::Reload failed: {}", err.to_string());
+                }
+            };
+
+            if let Err(e) = trap_exit_signals(reload) {
                 exit(&exit_tx_trap, e);
                 return;
             }
@@ -154,36 +477,208 @@ impl NetworkCommandHandler {
         loop {
             let command = self.receive_network_command()?;
 
+            if let Some(reply_tx) = command.reply_tx() {
+                if reply_tx.is_requester_gone() {
+                    debug!("Skipping network command of a requester that is gone");
+                    continue;
+                }
+            }
+
             match command {
-                NetworkCommand::Activate => {
-                    self.activate()?;
+                NetworkCommand::Activate { reply_tx } => {
+                    self.activate(&reply_tx);
                 }
-                NetworkCommand::Timeout => {
-                    if !self.activated {
-                        info!("Timeout reached. Exiting...");
-                        return Ok(());
+                NetworkCommand::TimeoutWarning { portal_session } => {
+                    if self.is_portal_session_idle(portal_session) {
+                        self.events.publish(Event::TimeoutApproaching {
+                            seconds: TIMEOUT_WARNING_SECS.min(self.config.activity_timeout),
+                        });
+                    }
+                }
+                NetworkCommand::Timeout { portal_session } => {
+                    if self.is_portal_session_idle(portal_session) {
+                        self.events.publish(Event::Timeout);
+
+                        if !self.config.daemon {
+                            info!("Timeout reached. Exiting...");
+                            return Ok(());
+                        }
+
+                        info!("Timeout reached. Stopping the portal...");
+                        self.stop_portal()?;
                     }
                 }
                 NetworkCommand::Exit => {
                     info!("Exiting...");
                     return Ok(());
                 }
+                NetworkCommand::Reload => {
+                    self.reload()?;
+                }
+                NetworkCommand::NetworkManagerRestarted => {
+                    self.recover_network_manager()?;
+                }
+                NetworkCommand::DeviceAdded => {
+                    self.device_added()?;
+                }
+                NetworkCommand::DeviceRemoved => {
+                    self.device_removed();
+                }
                 NetworkCommand::Connect {
                     ssid,
                     identity,
                     passphrase,
+                    reply_tx,
                 } => {
-                    if self.connect(&ssid, &identity, &passphrase)? {
+                    if self.portal_connection.is_none() {
+                        reply(
+                            &reply_tx,
+                            NetworkCommandResponse::Failed("Portal is not running".into()),
+                        );
+                        continue;
+                    }
+
+                    if self.target_removed {
+                        reply(
+                            &reply_tx,
+                            NetworkCommandResponse::Failed("Target WiFi device was removed".into()),
+                        );
+                        continue;
+                    }
+
+                    if let Err(e) = self.validate_credentials(&ssid, &identity, &passphrase) {
+                        let security = find_access_point(&self.access_points, &ssid)
+                            .map_or("unknown", get_network_security);
+                        self.metrics
+                            .connect_attempt("invalid_credentials", security);
+
+                        reply(&reply_tx, NetworkCommandResponse::InvalidCredentials(e));
+                        continue;
+                    }
+
+                    reply(&reply_tx, NetworkCommandResponse::Connecting);
+
+                    if self.connect(&ssid, &identity, &passphrase)? && !self.config.daemon {
+                        return Ok(());
+                    }
+                }
+                NetworkCommand::Status { reply_tx } => {
+                    reply(&reply_tx, NetworkCommandResponse::Status(self.status()));
+                }
+                NetworkCommand::Device { reply_tx } => {
+                    reply(
+                        &reply_tx,
+                        NetworkCommandResponse::Device(self.device_info()),
+                    );
+                }
+                NetworkCommand::StartPortal { reply_tx } => {
+                    if self.device_removed {
+                        reply(
+                            &reply_tx,
+                            NetworkCommandResponse::Failed("WiFi device was removed".into()),
+                        );
+                        continue;
+                    }
+
+                    if self.portal_connection.is_some() {
+                        reply(
+                            &reply_tx,
+                            NetworkCommandResponse::Failed("Portal is already running".into()),
+                        );
+                        continue;
+                    }
+
+                    // The networks scanned before may be long gone
+                    if let Err(e) = self.rescan() {
+                        warn!("Scanning before starting the portal failed: {}", e);
+                    }
+
+                    if let Err(e) = self.start_portal() {
+                        reply(&reply_tx, NetworkCommandResponse::Failed(e.to_string()));
+
+                        if !self.config.daemon {
+                            return Err(e);
+                        }
+
+                        // A daemon keeps waiting for commands, so that starting
+                        // the portal may be retried
+                        error!("Starting the portal failed: {}", e);
+
+                        if let Err(e) = self.stop_portal() {
+                            warn!("Cleaning up the portal failed: {}", e);
+                        }
+
+                        continue;
+                    }
+
+                    reply(&reply_tx, NetworkCommandResponse::Done);
+                }
+                NetworkCommand::StopPortal { reply_tx } => {
+                    if self.portal_connection.is_none() {
+                        reply(
+                            &reply_tx,
+                            NetworkCommandResponse::Failed("Portal is not running".into()),
+                        );
+                        continue;
+                    }
+
+                    self.stop_portal()?;
+
+                    reply(&reply_tx, NetworkCommandResponse::Done);
+
+                    if !self.config.daemon {
+                        info!("Portal stopped. Exiting...");
                         return Ok(());
                     }
                 }
+                NetworkCommand::SavedNetworks { reply_tx } => {
+                    let response = match self.saved_networks() {
+                        Ok(networks) => NetworkCommandResponse::SavedNetworks(networks),
+                        Err(e) => NetworkCommandResponse::Failed(e.to_string()),
+                    };
+
+                    reply(&reply_tx, response);
+                }
+                NetworkCommand::ForgetNetwork { ssid, reply_tx } => {
+                    let response = match self.forget_network(&ssid) {
+                        Ok(true) => NetworkCommandResponse::Done,
+                        Ok(false) => NetworkCommandResponse::Failed(format!(
+                            "No saved network with SSID '{}'",
+                            ssid
+                        )),
+                        Err(e) => NetworkCommandResponse::Failed(e.to_string()),
+                    };
+
+                    reply(&reply_tx, response);
+                }
+                NetworkCommand::Diagnostics { reply_tx } => {
+                    reply(
+                        &reply_tx,
+                        NetworkCommandResponse::Diagnostics(self.diagnostics()),
+                    );
+                }
+                NetworkCommand::Rescan { reply_tx } => {
+                    let response = match self.rescan() {
+                        Ok(()) => {
+                            NetworkCommandResponse::Networks(get_networks(&self.access_points))
+                        }
+                        Err(e) => NetworkCommandResponse::Failed(e.to_string()),
+                    };
+
+                    reply(&reply_tx, response);
+                }
             }
         }
     }
 
     fn receive_network_command(&self) -> Result<NetworkCommand> {
+        watchdog_idle();
+
         match self.network_rx.recv() {
-            Ok(command) => Ok(command),
+            Ok(command) => {
+                watchdog_progress();
+                Ok(command)
+            }
             Err(e) => {
                 // Sleep for a second, so that other threads may log error info.
                 thread::sleep(Duration::from_secs(1));
@@ -193,83 +688,649 @@ impl NetworkCommandHandler {
     }
 
     fn stop(&mut self, exit_tx: &Sender<ExitResult>, result: ExitResult) {
-        let _ = stop_dnsmasq(&mut self.dnsmasq);
+        if let Some(ref mut dnsmasq) = self.dnsmasq {
+            let _ = stop_dnsmasq(dnsmasq);
+        }
 
         if let Some(ref connection) = self.portal_connection {
             let _ = stop_portal_impl(connection, &self.config);
         }
 
+        stop_control_server(&self.config.control_socket);
+
+        self.events.publish(Event::Exit);
+
+        // The process exits right after, so the exit hook has to finish first
+        if let Some(hooks) = self.hooks.take() {
+            hooks.wait();
+        }
+
         let _ = exit_tx.send(result);
     }
 
-    fn activate(&mut self) -> ExitResult {
+    fn start_portal(&mut self) -> Result<()> {
+        // Unlike the listed networks, congestion counts hidden access points
+        // and every access point of a network
+        let nearby = nearby_access_points(self.station_device());
+
+        self.portal_connection = Some(create_portal(&self.device, &self.config, &nearby)?);
+        self.metrics.portal_started();
+
+        if self.dnsmasq.is_none() {
+            self.dnsmasq = Some(start_dnsmasq(&self.config, &self.device)?);
+            self.metrics.dnsmasq_started();
+        }
+
+        self.spawn_server();
+
+        self.activated = false;
+        self.portal_session += 1;
+
+        Self::spawn_activity_timeout(&self.config, self.network_tx.clone(), self.portal_session);
+
+        self.set_state(State::Portal);
+
+        self.events.publish(Event::PortalStarted {
+            ssid: self.config.ssid.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Stops the access point and DHCP server, e.g. on timeout or on request
+    /// over the control socket
+    fn stop_portal(&mut self) -> Result<()> {
+        self.stop_dnsmasq();
+
+        if let Some(connection) = self.portal_connection.take() {
+            stop_portal(&connection, &self.config)?;
+        }
+
+        self.set_state(State::Idle);
+
+        Ok(())
+    }
+
+    fn stop_dnsmasq(&mut self) {
+        if let Some(mut dnsmasq) = self.dnsmasq.take() {
+            if let Err(e) = stop_dnsmasq(&mut dnsmasq) {
+                warn!("Stopping dnsmasq failed: {}", e);
+            }
+        }
+    }
+
+    /// A timeout applies only to the portal session it was started for and
+    /// only if no user has connected to the portal
+    fn is_portal_session_idle(&self, portal_session: u64) -> bool {
+        portal_session == self.portal_session && self.portal_connection.is_some() && !self.activated
+    }
+
+    fn saved_networks(&self) -> Result<Vec<SavedNetwork>> {
+        let connections = self.manager.get_connections()?;
+
+        Ok(connections
+            .iter()
+            .filter(|connection| {
+                is_wifi_connection(connection) && !is_access_point_connection(connection)
+            })
+            .filter_map(|connection| {
+                connection_ssid_as_str(connection).map(|ssid| SavedNetwork {
+                    ssid: ssid.to_string(),
+                    id: connection.settings().id.clone(),
+                    uuid: connection.settings().uuid.clone(),
+                })
+            })
+            .collect())
+    }
+
+    /// Returns whether a saved network with the SSID was found
+    fn forget_network(&self, ssid: &str) -> Result<bool> {
+        let mut found = false;
+
+        for connection in &self.manager.get_connections()? {
+            if is_wifi_connection(connection)
+                && !is_access_point_connection(connection)
+                && is_same_ssid(connection, ssid)
+            {
+                info!("Forgetting WiFi network '{}'", ssid);
+                connection.delete()?;
+                found = true;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Scanning is not possible while the device is in access point mode, in
+    /// which case the networks found before starting the portal are kept,
+    /// unless a separate target device scans
+    fn rescan(&mut self) -> Result<()> {
+        if self.portal_connection.is_none() || self.target_device.is_some() {
+            let wifi_device = self.station_device().as_wifi_device().unwrap();
+            wifi_device.request_scan()?;
+        }
+
+        self.refresh_access_points()
+    }
+
+    fn activate(&mut self, reply_tx: &ReplyTx) {
+        if !self.activated {
+            self.events.publish(Event::Activated);
+        }
+
         self.activated = true;
 
         let networks = get_networks(&self.access_points);
 
-        self.server_tx
-            .send(NetworkCommandResponse::Networks(networks))
-            .chain_err(|| ErrorKind::SendAccessPointSSIDs)
+        reply(reply_tx, NetworkCommandResponse::Networks(networks));
     }
 
-    fn connect(&mut self, ssid: &str, identity: &str, passphrase: &str) -> Result<bool> {
-        delete_existing_connections_to_same_network(&self.manager, ssid);
+    fn status(&self) -> Status {
+        Status {
+            state: self.state,
+            portal_ssid: self.config.ssid.clone(),
+            activated: self.activated,
+            last_connection_attempt: self.last_connection_attempt.clone(),
+        }
+    }
 
-        if let Some(ref connection) = self.portal_connection {
-            stop_portal(connection, &self.config)?;
+    /// Scans and connects to the chosen network
+    fn station_device(&self) -> &Device {
+        self.target_device.as_ref().unwrap_or(&self.device)
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let state = match self.device.get_state() {
+            Ok(state) => format!("{:?}", state).to_lowercase(),
+            Err(e) => {
+                warn!("Getting device state failed: {}", e);
+                "unknown".into()
+            }
+        };
+
+        let interface = self.device.interface();
+
+        DeviceInfo {
+            interface: interface.to_string(),
+            state,
+            mac_address: mac_address(interface),
+            driver: driver(interface),
+            capabilities: device_capabilities(interface),
+            target_interface: self
+                .target_device
+                .as_ref()
+                .map(|device| device.interface().to_string()),
+            target_removed: self.target_removed,
+            regulatory_domain: regulatory_domain(),
         }
+    }
 
-        self.portal_connection = None;
+    /// The portal is paused while the WiFi device is gone, e.g. when a USB
+    /// dongle is unplugged. Without the target device the portal keeps
+    /// running, but connecting fails until it is added again.
+    fn device_removed(&mut self) {
+        if !self.target_removed {
+            let target_present = match self.target_device {
+                Some(ref target_device) => self.is_device_present(target_device),
+                None => true,
+            };
+
+            if !target_present {
+                warn!("Target WiFi device removed");
+                self.target_removed = true;
+            }
+        }
 
-        self.access_points = get_access_points(&self.device)?;
+        if self.device_removed || self.is_device_present(&self.device) {
+            return;
+        }
 
-        if let Some(access_point) = find_access_point(&self.access_points, ssid) {
-            let wifi_device = self.device.as_wifi_device().unwrap();
+        warn!("WiFi device removed");
 
-            info!("Connecting to access point '{}'...", ssid);
+        self.device_removed = true;
 
-            let credentials = init_access_point_credentials(access_point, identity, passphrase);
+        if self.portal_connection.take().is_some() {
+            info!("Pausing the portal until a WiFi device is added");
+            self.portal_paused = true;
+            self.stop_dnsmasq();
+        }
 
-            match wifi_device.connect(access_point, &credentials) {
-                Ok((connection, state)) => {
-                    if state == ConnectionState::Activated {
-                        match wait_for_connectivity(&self.manager, 20) {
-                            Ok(has_connectivity) => {
-                                if has_connectivity {
-                                    info!("Internet connectivity established");
-                                } else {
-                                    warn!("Cannot establish Internet connectivity");
-                                }
-                            }
-                            Err(err) => error!("Getting Internet connectivity failed: {}", err),
-                        }
+        self.set_state(State::Idle);
+    }
 
-                        return Ok(true);
-                    }
+    fn device_added(&mut self) -> Result<()> {
+        if !self.device_removed {
+            if self.target_removed {
+                self.target_device_added();
+            }
 
-                    if let Err(err) = connection.delete() {
-                        error!("Deleting connection object failed: {}", err)
-                    }
+            return Ok(());
+        }
+
+        let (device, target_device) =
+            match wait_for_devices(&self.manager, &self.config, DEVICE_ADDED_TIMEOUT_SECS) {
+                Ok(devices) => devices,
+                Err(e) => {
+                    debug!("Added device is not usable: {}", e);
+                    return Ok(());
+                }
+            };
 
+        logger::set_context("interface", device.interface());
+
+        self.device = device;
+        self.target_device = target_device;
+        self.device_removed = false;
+        self.target_removed = false;
+
+        if self.portal_paused {
+            info!("Resuming the portal");
+            self.portal_paused = false;
+
+            delete_exising_wifi_connect_ap_profile(&self.config.ssid)
+                .chain_err(|| ErrorKind::DeleteAccessPoint)?;
+
+            self.start_portal()?;
+        }
+
+        Ok(())
+    }
+
+    /// The portal device keeps running the portal, so only the target device
+    /// is selected again
+    fn target_device_added(&mut self) {
+        match select_device(&self.manager, &self.config, DeviceRole::Target) {
+            Ok(target_device) => {
+                self.target_device = Some(target_device);
+                self.target_removed = false;
+            }
+            Err(e) => debug!("Added device is not usable as target device: {}", e),
+        }
+    }
+
+    fn is_device_present(&self, device: &Device) -> bool {
+        match self.manager.get_devices() {
+            Ok(devices) => devices
+                .iter()
+                .any(|present| present.interface() == device.interface()),
+            Err(e) => {
+                warn!("Getting devices failed: {}", e);
+                true
+            }
+        }
+    }
+
+    /// The device and connection handles of the previous NetworkManager
+    /// instance are stale, so they are resolved again and the portal is
+    /// recreated if it was running. Commands received meanwhile wait.
+    fn recover_network_manager(&mut self) -> Result<()> {
+        let portal_running = self.portal_connection.take().is_some();
+
+        self.stop_dnsmasq();
+
+        for attempt in 1..=NETWORK_MANAGER_RECOVERY_ATTEMPTS {
+            match self.reconnect_network_manager(portal_running) {
+                Ok(()) => {
+                    info!("Recovered from NetworkManager restart");
+                    return Ok(());
+                }
+                Err(e) => {
                     warn!(
-                        "Connection to access point not activated '{}': {:?}",
-                        ssid, state
+                        "Recovering from NetworkManager restart failed ({}/{}): {}",
+                        attempt, NETWORK_MANAGER_RECOVERY_ATTEMPTS, e
                     );
+                    thread::sleep(Duration::from_secs(NETWORK_MANAGER_RECOVERY_DELAY_SECS));
+                    watchdog_progress();
+                }
+            }
+        }
+
+        bail!(ErrorKind::NetworkManagerRecovery)
+    }
+
+    fn reconnect_network_manager(&mut self, portal_running: bool) -> Result<()> {
+        wait_for_network_manager(self.config.network_manager_timeout)?;
+
+        self.manager = NetworkManager::new();
+        let (device, target_device) = find_devices(&self.manager, &self.config)?;
+
+        self.device = device;
+        self.target_device = target_device;
+
+        if portal_running {
+            delete_exising_wifi_connect_ap_profile(&self.config.ssid)
+                .chain_err(|| ErrorKind::DeleteAccessPoint)?;
+
+            self.start_portal()?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies what can be applied while running. The hotspot and dnsmasq are
+    /// restarted only if the portal SSID, passphrase, channel or addressing
+    /// changed.
+    fn reload(&mut self) -> Result<()> {
+        info!("Reloading configuration...");
+
+        let config = match get_config() {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "Reloading configuration failed. Keeping the current one: {}",
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        if config.portal_band == Some(PortalBand::Band5Ghz)
+            && !device_capabilities(self.device.interface()).band_5ghz
+        {
+            error!(
+                "Reloading configuration failed. Keeping the current one: {}",
+                Error::from(ErrorKind::No5GhzSupport(self.device.interface().into()))
+            );
+            return Ok(());
+        }
+
+        let mut applied = self.config.clone();
+        applied.ssid = config.ssid.clone();
+        applied.passphrase = config.passphrase.clone();
+        applied.portal_band = config.portal_band;
+        applied.portal_channel = config.portal_channel;
+        applied.gateway = config.gateway;
+        applied.dhcp_range = config.dhcp_range.clone();
+        applied.listening_port = config.listening_port;
+        applied.activity_timeout = config.activity_timeout;
+        applied.ui_directory = config.ui_directory.clone();
+        applied.hooks = config.hooks.clone();
+        applied.log.filter = config.log.filter.clone();
+
+        if applied != config {
+            warn!("Some of the changed settings apply only after restarting WiFi Connect");
+        }
+
+        if applied == self.config {
+            info!("Configuration unchanged");
+            return Ok(());
+        }
+
+        if applied.log.filter != self.config.log.filter {
+            logger::set_filter(&applied.log.filter);
+        }
+
+        if applied.hooks != self.config.hooks {
+            match start_hooks(&applied.hooks, &self.events) {
+                Ok(hooks) => {
+                    if let Some(previous) = mem::replace(&mut self.hooks, hooks) {
+                        previous.stop();
+                    }
                 }
                 Err(e) => {
-                    warn!("Error connecting to access point '{}': {}", ssid, e);
+                    warn!("Keeping the current hooks: {}", e);
+                    applied.hooks = self.config.hooks.clone();
                 }
             }
         }
 
-        self.access_points = get_access_points(&self.device)?;
+        let restart_portal = applied.ssid != self.config.ssid
+            || applied.passphrase != self.config.passphrase
+            || applied.portal_band != self.config.portal_band
+            || applied.portal_channel != self.config.portal_channel
+            || applied.gateway != self.config.gateway
+            || applied.dhcp_range != self.config.dhcp_range;
+
+        let timeout_changed = applied.activity_timeout != self.config.activity_timeout;
+
+        self.config = applied;
+        *self.shared_config.write().unwrap() = self.config.clone();
+
+        if self.portal_connection.is_none() {
+            return Ok(());
+        }
+
+        if restart_portal {
+            info!("Restarting the portal...");
+            self.stop_portal()?;
+            self.start_portal()?;
+        } else {
+            self.spawn_server();
+
+            // Restarts the activity timeout, the previous one is ignored
+            if timeout_changed && !self.activated {
+                self.portal_session += 1;
+                Self::spawn_activity_timeout(
+                    &self.config,
+                    self.network_tx.clone(),
+                    self.portal_session,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collected on a best effort basis, as it is most useful when something
+    /// is already failing
+    fn diagnostics(&self) -> NetworkDiagnostics {
+        let network_manager = NetworkManagerInfo {
+            state: debug_or_unknown(self.manager.get_state()),
+            connectivity: debug_or_unknown(self.manager.get_connectivity()),
+            networking_enabled: self.manager.is_networking_enabled().ok(),
+            wireless_enabled: self.manager.is_wireless_enabled().ok(),
+        };
+
+        let saved_networks = self.saved_networks().unwrap_or_else(|e| {
+            warn!("Getting saved networks failed: {}", e);
+            Vec::new()
+        });
+
+        NetworkDiagnostics {
+            status: self.status(),
+            device: self.device_info(),
+            network_manager,
+            networks: get_networks(&self.access_points),
+            saved_networks,
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        debug!("State changed: {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.metrics.set_state(state);
+
+        logger::set_context("phase", state.as_str());
+
+        notify_status(&match state {
+            State::Idle => "Idle".to_string(),
+            State::Portal => format!("Captive portal '{}' open", self.config.ssid),
+            State::Connecting => "Connecting".to_string(),
+            State::Connected => "Connected".to_string(),
+        });
+
+        if let State::Idle | State::Portal = state {
+            logger::clear_context("ssid");
+        }
+
+        self.events.publish(Event::StateChanged { state });
+    }
+
+    fn refresh_access_points(&mut self) -> Result<()> {
+        let started = Instant::now();
+
+        self.access_points = get_access_points(self.station_device())?;
+
+        self.metrics.scanned(started.elapsed());
+
+        self.events.publish(Event::ScanResults {
+            networks: get_networks(&self.access_points),
+        });
+
+        Ok(())
+    }
+
+    fn record_connection_attempt(&mut self, ssid: &str, error: Option<String>) {
+        self.last_connection_attempt = Some(ConnectionAttempt {
+            ssid: ssid.to_string(),
+            success: error.is_none(),
+            error,
+        });
+    }
+
+    /// Networks not in the last scan results are not validated, as their
+    /// security type is unknown until after the portal is stopped
+    fn validate_credentials(
+        &self,
+        ssid: &str,
+        identity: &str,
+        passphrase: &str,
+    ) -> ::std::result::Result<(), ValidationError> {
+        match find_access_point(&self.access_points, ssid) {
+            Some(access_point) => {
+                validate_credentials(get_network_security(access_point), identity, passphrase)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn connect(&mut self, ssid: &str, identity: &str, passphrase: &str) -> Result<bool> {
+        logger::set_context("ssid", ssid);
+
+        self.events.publish(Event::ConnectStarted {
+            ssid: ssid.to_string(),
+        });
+
+        delete_existing_connections_to_same_network(&self.manager, ssid);
+
+        // With a separate target device the portal stays up while connecting
+        let keep_portal = self.target_device.is_some();
+
+        if !keep_portal {
+            if self.portal_connection.is_some() {
+                self.events.publish(Event::PortalStopping {
+                    seconds: PORTAL_STOP_DELAY_SECS,
+                });
+
+                thread::sleep(Duration::from_secs(PORTAL_STOP_DELAY_SECS));
+                watchdog_progress();
+            }
+
+            if let Some(ref connection) = self.portal_connection {
+                stop_portal(connection, &self.config)?;
+            }
+
+            self.portal_connection = None;
+        }
+
+        self.set_state(State::Connecting);
+
+        self.refresh_access_points()?;
+
+        let started = Instant::now();
+
+        let (outcome, security, error) =
+            if let Some(access_point) = find_access_point(&self.access_points, ssid) {
+                let security = get_network_security(access_point);
+
+                let wifi_device = self.station_device().as_wifi_device().unwrap();
+
+                info!("Connecting to access point '{}'...", ssid);
+
+                let credentials = init_access_point_credentials(access_point, identity, passphrase);
+
+                let result = wifi_device.connect(access_point, &credentials);
+                watchdog_progress();
+
+                match result {
+                    Ok((connection, state)) => {
+                        if state == ConnectionState::Activated {
+                            match wait_for_connectivity(&self.manager, 20) {
+                                Ok(has_connectivity) => {
+                                    if has_connectivity {
+                                        info!("Internet connectivity established");
+                                    } else {
+                                        warn!("Cannot establish Internet connectivity");
+                                    }
+                                }
+                                Err(err) => error!("Getting Internet connectivity failed: {}", err),
+                            }
+
+                            self.stop_dnsmasq();
+
+                            if let Some(connection) = self.portal_connection.take() {
+                                stop_portal(&connection, &self.config)?;
+                            }
+
+                            self.metrics.connect_attempt("success", security);
+                            self.metrics.connected(started.elapsed());
+
+                            self.record_connection_attempt(ssid, None);
+                            self.set_state(State::Connected);
+
+                            self.events.publish(Event::Connected {
+                                ssid: ssid.to_string(),
+                            });
+
+                            return Ok(true);
+                        }
+
+                        if let Err(err) = connection.delete() {
+                            error!("Deleting connection object failed: {}", err)
+                        }
 
-        self.portal_connection = Some(create_portal(&self.device, &self.config)?);
+                        warn!(
+                            "Connection to access point not activated '{}': {:?}",
+                            ssid, state
+                        );
+
+                        let error = format!("Connection not activated: {:?}", state);
+
+                        ("not_activated", security, error)
+                    }
+                    Err(e) => {
+                        warn!("Error connecting to access point '{}': {}", ssid, e);
+
+                        ("error", security, format!("Error connecting: {}", e))
+                    }
+                }
+            } else {
+                warn!("Access point '{}' not found", ssid);
+
+                ("not_found", "unknown", "Access point not found".to_string())
+            };
+
+        self.metrics.connect_attempt(outcome, security);
+
+        self.events.publish(Event::ConnectFailed {
+            ssid: ssid.to_string(),
+            reason: error.clone(),
+        });
+
+        self.record_connection_attempt(ssid, Some(error));
+
+        self.refresh_access_points()?;
+
+        // Restarting the portal also restarts the activity timeout and
+        // announces the portal again
+        if keep_portal {
+            self.set_state(State::Portal);
+        } else {
+            self.start_portal()?;
+        }
 
         Ok(false)
     }
 }
 
+/// The requester may have timed out already, which is not an error for the
+/// network thread
+fn reply(reply_tx: &ReplyTx, response: NetworkCommandResponse) {
+    if reply_tx.tx.send(response).is_err() {
+        debug!("Network command requester is gone");
+    }
+}
+
 fn init_access_point_credentials(
     access_point: &AccessPoint,
     identity: &str,
@@ -308,50 +1369,199 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
 }
 
 pub fn init_networking(config: &Config) -> Result<()> {
-    start_network_manager_service()?;
+    start_network_manager(config)?;
+
+    init_regulatory_domain(config)?;
 
     delete_exising_wifi_connect_ap_profile(&config.ssid).chain_err(|| ErrorKind::DeleteAccessPoint)
 }
 
-pub fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
-    if let Some(ref interface) = *interface {
-        let device = manager
-            .get_device_by_interface(interface)
-            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
+/// Waits for a device to appear, e.g. a USB dongle still being enumerated.
+/// Without a timeout a missing device is an error right away.
+fn wait_for_devices(
+    manager: &NetworkManager,
+    config: &Config,
+    timeout: u64,
+) -> Result<(Device, Option<Device>)> {
+    let started = Instant::now();
+    let mut waiting = false;
 
-        info!("Targeted WiFi device: {}", interface);
+    loop {
+        match find_devices(manager, config) {
+            Ok(devices) => return Ok(devices),
+            Err(e) => {
+                if started.elapsed() >= Duration::from_secs(timeout) {
+                    return Err(e);
+                }
 
-        if *device.device_type() != DeviceType::WiFi {
-            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
+                if !waiting {
+                    info!("Waiting for a WiFi device...");
+                    waiting = true;
+                }
+            }
         }
 
-        if device.get_state()? == DeviceState::Unmanaged {
-            bail!(ErrorKind::UnmanagedDevice(interface.clone()))
+        thread::sleep(Duration::from_secs(DEVICE_POLL_INTERVAL_SECS));
+        watchdog_progress();
+    }
+}
+
+/// The portal device, and with `--target-interface` the device connecting to
+/// the chosen network
+fn find_devices(manager: &NetworkManager, config: &Config) -> Result<(Device, Option<Device>)> {
+    let device = select_device(manager, config, DeviceRole::Portal)?;
+
+    let target_device = if config.target_interface.is_some() {
+        let target_device = select_device(manager, config, DeviceRole::Target)?;
+
+        if target_device.interface() == device.interface() {
+            bail!(ErrorKind::SameTargetInterface(device.interface().into()));
         }
 
-        Ok(device)
+        Some(target_device)
     } else {
-        let devices = manager.get_devices()?;
+        None
+    };
 
-        if let Some(device) = find_wifi_managed_device(devices)? {
-            info!("WiFi device: {}", device.interface());
-            Ok(device)
-        } else {
-            bail!(ErrorKind::NoWiFiDevice)
-        }
-    }
+    Ok((device, target_device))
 }
 
-fn find_wifi_managed_device(devices: Vec<Device>) -> Result<Option<Device>> {
+/// Picks the best WiFi device matching the `--portal-interface` or
+/// `--target-interface` selector. Portal devices without AP mode are skipped,
+/// and managed devices, AP/STA concurrency and 5GHz support are preferred in
+/// that order.
+fn select_device(manager: &NetworkManager, config: &Config, role: DeviceRole) -> Result<Device> {
+    let selector = match role {
+        DeviceRole::Portal => &config.interface,
+        DeviceRole::Target => &config.target_interface,
+    }
+    .as_ref()
+    .map(|selector| DeviceSelector::parse(selector));
+
+    // A portal device chosen automatically must not be the target device
+    let excluded = match role {
+        DeviceRole::Portal => config
+            .target_interface
+            .as_ref()
+            .map(|selector| DeviceSelector::parse(selector)),
+        DeviceRole::Target => None,
+    };
+
+    let devices = match selector {
+        Some(DeviceSelector::Interface(ref interface)) => {
+            let device = manager
+                .get_device_by_interface(interface)
+                .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
+
+            info!("Targeted WiFi device: {}", interface);
+
+            if *device.device_type() != DeviceType::WiFi {
+                bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
+            }
+
+            vec![device]
+        }
+        _ => manager
+            .get_devices()?
+            .into_iter()
+            .filter(|device| *device.device_type() == DeviceType::WiFi)
+            .filter(|device| match selector {
+                Some(ref selector) => selector.matches(device.interface()),
+                None => true,
+            })
+            .filter(|device| match excluded {
+                Some(ref excluded) => !excluded.matches(device.interface()),
+                None => true,
+            })
+            .collect(),
+    };
+
+    let mut candidates = Vec::new();
+    let mut skipped = None;
+
     for device in devices {
-        if *device.device_type() == DeviceType::WiFi
-            && device.get_state()? != DeviceState::Unmanaged
+        let managed = device.get_state()? != DeviceState::Unmanaged;
+
+        if !managed && !config.manage_device {
+            debug!("Skipping unmanaged WiFi device {}", device.interface());
+            skipped = Some(ErrorKind::UnmanagedDevice(device.interface().into()));
+            continue;
+        }
+
+        let capabilities = device_capabilities(device.interface());
+
+        debug!("WiFi device {}: {:?}", device.interface(), capabilities);
+
+        if role == DeviceRole::Portal && !capabilities.access_point {
+            warn!(
+                "WiFi device {} does not support access point mode",
+                device.interface()
+            );
+            skipped = Some(ErrorKind::NoAccessPointMode(device.interface().into()));
+            continue;
+        }
+
+        if role == DeviceRole::Portal
+            && config.portal_band == Some(PortalBand::Band5Ghz)
+            && !capabilities.band_5ghz
         {
-            return Ok(Some(device));
+            warn!("WiFi device {} does not support 5GHz", device.interface());
+            skipped = Some(ErrorKind::No5GhzSupport(device.interface().into()));
+            continue;
+        }
+
+        candidates.push((managed, capabilities, device));
+    }
+
+    // Reversed, so that the first of equally good devices is chosen
+    let best = candidates
+        .into_iter()
+        .rev()
+        .max_by_key(|&(managed, ref capabilities, _)| {
+            (
+                managed,
+                capabilities.ap_sta_concurrency,
+                capabilities.band_5ghz,
+            )
+        });
+
+    let (managed, device) = match (best, skipped, selector) {
+        (Some((managed, _, device)), _, _) => (managed, device),
+        (None, Some(skipped), _) => bail!(skipped),
+        (None, None, Some(selector)) => bail!(ErrorKind::DeviceBySelector(selector.to_string())),
+        (None, None, None) => bail!(ErrorKind::NoWiFiDevice),
+    };
+
+    match role {
+        DeviceRole::Portal => info!("WiFi device: {}", device.interface()),
+        DeviceRole::Target => info!("Target WiFi device: {}", device.interface()),
+    }
+
+    if !managed {
+        manage_device(&device)?;
+    }
+
+    Ok(device)
+}
+
+/// Hands an unmanaged device over to NetworkManager with `--manage-device`
+fn manage_device(device: &Device) -> Result<()> {
+    info!("Making {} managed by NetworkManager", device.interface());
+
+    set_device_managed(device.interface())?;
+
+    let started = Instant::now();
+
+    while device.get_state()? == DeviceState::Unmanaged {
+        if started.elapsed() >= Duration::from_secs(MANAGE_DEVICE_TIMEOUT_SECS) {
+            bail!(ErrorKind::ManageDevice(device.interface().into()));
         }
+
+        thread::sleep(Duration::from_secs(DEVICE_POLL_INTERVAL_SECS));
+        watchdog_progress();
     }
 
-    Ok(None)
+    Ok(())
 }
 
 fn get_access_points(device: &Device) -> Result<Vec<AccessPoint>> {
@@ -388,12 +1598,23 @@ fn get_access_points_impl(device: &Device) -> Result<Vec<AccessPoint>> {
         retries += 1;
         debug!("No access points found - retry #{}", retries);
         thread::sleep(Duration::from_secs(1));
+        watchdog_progress();
     }
 
     warn!("No access points found - giving up...");
     Ok(vec![])
 }
 
+fn nearby_access_points(device: &Device) -> Vec<AccessPoint> {
+    match device.as_wifi_device().unwrap().get_access_points() {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            warn!("Getting access points failed: {}", e);
+            Vec::new()
+        }
+    }
+}
+
 fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
     access_points
         .iter()
@@ -412,7 +1633,7 @@ fn get_network_info(access_point: &AccessPoint) -> Network {
     }
 }
 
-fn get_network_security(access_point: &AccessPoint) -> &str {
+fn get_network_security(access_point: &AccessPoint) -> &'static str {
     if access_point.security.contains(Security::ENTERPRISE) {
         "enterprise"
     } else if access_point.security.contains(Security::WPA2)
@@ -438,11 +1659,27 @@ fn find_access_point<'a>(access_points: &'a [AccessPoint], ssid: &str) -> Option
     None
 }
 
-fn create_portal(device: &Device, config: &Config) -> Result<Connection> {
+fn create_portal(
+    device: &Device,
+    config: &Config,
+    access_points: &[AccessPoint],
+) -> Result<Connection> {
     let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
 
-    create_portal_impl(device, &config.ssid, &config.gateway, &portal_passphrase)
-        .chain_err(|| ErrorKind::CreateCaptivePortal)
+    let channel = portal_channel(
+        config,
+        device_capabilities(device.interface()).band_5ghz,
+        access_points,
+    );
+
+    create_portal_impl(
+        device,
+        &config.ssid,
+        &config.gateway,
+        &portal_passphrase,
+        channel,
+    )
+    .chain_err(|| ErrorKind::CreateCaptivePortal)
 }
 
 fn create_portal_impl(
@@ -450,14 +1687,68 @@ fn create_portal_impl(
     ssid: &str,
     gateway: &Ipv4Addr,
     passphrase: &Option<&str>,
+    channel: Option<(PortalBand, Option<u32>)>,
 ) -> Result<Connection> {
     info!("Starting access point...");
-    let wifi_device = device.as_wifi_device().unwrap();
-    let (portal_connection, _) = wifi_device.create_hotspot(ssid, *passphrase, Some(*gateway))?;
+
+    let portal_connection = match channel {
+        Some((band, channel)) => {
+            create_hotspot_on_channel(device, ssid, gateway, passphrase, band, channel)?
+        }
+        None => {
+            let wifi_device = device.as_wifi_device().unwrap();
+            wifi_device
+                .create_hotspot(ssid, *passphrase, Some(*gateway))?
+                .0
+        }
+    };
+
     info!("Access point '{}' created", ssid);
     Ok(portal_connection)
 }
 
+/// `create_hotspot` has no band or channel, so the same profile is added with
+/// them and activated afterwards
+fn create_hotspot_on_channel(
+    device: &Device,
+    ssid: &str,
+    gateway: &Ipv4Addr,
+    passphrase: &Option<&str>,
+    band: PortalBand,
+    channel: Option<u32>,
+) -> Result<Connection> {
+    let band = match band {
+        PortalBand::Band5Ghz => "a",
+        PortalBand::Band2_4Ghz | PortalBand::Auto => "bg",
+    };
+
+    match channel {
+        Some(channel) => info!("Access point band '{}', channel {}", band, channel),
+        None => info!("Access point band '{}'", band),
+    }
+
+    add_hotspot_connection(&HotspotSettings {
+        interface: device.interface(),
+        ssid,
+        passphrase: *passphrase,
+        gateway: *gateway,
+        band,
+        channel,
+    })?;
+
+    let manager = NetworkManager::new();
+
+    let connection = manager
+        .get_connections()?
+        .into_iter()
+        .find(|connection| is_access_point_connection(connection) && is_same_ssid(connection, ssid))
+        .ok_or("Added hotspot connection not found")?;
+
+    connection.activate()?;
+
+    Ok(connection)
+}
+
 fn stop_portal(connection: &Connection, config: &Config) -> Result<()> {
     stop_portal_impl(connection, config).chain_err(|| ErrorKind::StopAccessPoint)
 }
@@ -494,6 +1785,7 @@ fn wait_for_connectivity(manager: &NetworkManager, timeout: u64) -> Result<bool>
         }
 
         ::std::thread::sleep(::std::time::Duration::from_secs(1));
+        watchdog_progress();
 
         total_time += 1;
 
@@ -504,30 +1796,6 @@ fn wait_for_connectivity(manager: &NetworkManager, timeout: u64) -> Result<bool>
     }
 }
 
-pub fn start_network_manager_service() -> Result<()> {
-    let state = match NetworkManager::get_service_state() {
-        Ok(state) => state,
-        _ => {
-            info!("Cannot get the NetworkManager service state");
-            return Ok(());
-        }
-    };
-
-    if state != ServiceState::Active {
-        let state =
-            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
-        if state != ServiceState::Active {
-            bail!(ErrorKind::StartActiveNetworkManager);
-        } else {
-            info!("NetworkManager service started successfully");
-        }
-    } else {
-        debug!("NetworkManager service already running");
-    }
-
-    Ok(())
-}
-
 fn delete_exising_wifi_connect_ap_profile(ssid: &str) -> Result<()> {
     let manager = NetworkManager::new();
 
@@ -571,6 +1839,13 @@ fn is_same_ssid(connection: &Connection, ssid: &str) -> bool {
     connection_ssid_as_str(connection) == Some(ssid)
 }
 
+fn debug_or_unknown<T: ::std::fmt::Debug, E>(value: ::std::result::Result<T, E>) -> String {
+    match value {
+        Ok(value) => format!("{:?}", value).to_lowercase(),
+        Err(_) => "unknown".into(),
+    }
+}
+
 fn connection_ssid_as_str(connection: &Connection) -> Option<&str> {
     // An access point SSID could be random bytes and not a UTF-8 encoded string
     connection.settings().ssid.as_str().ok()
@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dbus::arg::{RefArg, Variant};
+use dbus::{BusType, Connection, ConnectionItem, Message, Path};
+
+use errors::*;
+use network::NetworkCommand;
+use systemd::watchdog_progress;
+
+pub const NM_BUS_NAME: &str = "org.freedesktop.NetworkManager";
+
+const DBUS_BUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const DBUS_INTERFACE: &str = "org.freedesktop.DBus";
+
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_INTERFACE: &str = "org.freedesktop.NetworkManager";
+const NM_DEVICE_INTERFACE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_WIRELESS_INTERFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT_INTERFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const NM_SETTINGS_INTERFACE: &str = "org.freedesktop.NetworkManager.Settings";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const METHOD_CALL_TIMEOUT_MS: i32 = 2000;
+
+const BUS_NAME_POLL_INTERVAL_MS: u64 = 500;
+
+const SIGNAL_POLL_INTERVAL_MS: i32 = 1000;
+
+const RECONNECT_DELAY_SECS: u64 = 1;
+
+type VariantMap = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// A hotspot profile as created by `WiFiDevice::create_hotspot`, which has no
+/// band or channel
+pub struct HotspotSettings<'a> {
+    pub interface: &'a str,
+    pub ssid: &'a str,
+    pub passphrase: Option<&'a str>,
+    pub gateway: Ipv4Addr,
+    /// `bg` or `a`
+    pub band: &'a str,
+    pub channel: Option<u32>,
+}
+
+const NAME_OWNER_CHANGED_MATCH: &str = "type='signal',sender='org.freedesktop.DBus',\
+     interface='org.freedesktop.DBus',member='NameOwnerChanged',\
+     arg0='org.freedesktop.NetworkManager'";
+
+const DEVICE_ADDED_MATCH: &str = "type='signal',sender='org.freedesktop.NetworkManager',\
+     interface='org.freedesktop.NetworkManager',member='DeviceAdded'";
+
+const DEVICE_REMOVED_MATCH: &str = "type='signal',sender='org.freedesktop.NetworkManager',\
+     interface='org.freedesktop.NetworkManager',member='DeviceRemoved'";
+
+/// NetworkManager is usable once it owns its bus name, which may take a while
+/// after its service is started
+pub fn wait_for_network_manager(timeout: u64) -> Result<()> {
+    let connection = Connection::get_private(BusType::System)
+        .chain_err(|| ErrorKind::NetworkManagerNotRunning(timeout))?;
+
+    let started = Instant::now();
+
+    if !is_network_manager_running(&connection) {
+        info!("Waiting for NetworkManager...");
+    }
+
+    while !is_network_manager_running(&connection) {
+        if started.elapsed() >= Duration::from_secs(timeout) {
+            bail!(ErrorKind::NetworkManagerNotRunning(timeout));
+        }
+
+        thread::sleep(Duration::from_millis(BUS_NAME_POLL_INTERVAL_MS));
+        watchdog_progress();
+    }
+
+    debug!("NetworkManager is running");
+
+    Ok(())
+}
+
+pub fn is_network_manager_running(connection: &Connection) -> bool {
+    let message =
+        match Message::new_method_call(DBUS_BUS_NAME, DBUS_PATH, DBUS_INTERFACE, "NameHasOwner") {
+            Ok(message) => message.append1(NM_BUS_NAME),
+            Err(e) => {
+                warn!("Creating D-Bus method call failed: {}", e);
+                return false;
+            }
+        };
+
+    match connection.send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS) {
+        Ok(reply) => reply.read1::<bool>().unwrap_or(false),
+        Err(e) => {
+            debug!("Checking the NetworkManager bus name failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Sets the `WirelessEnabled` property, the same as `nmcli radio wifi on`
+pub fn enable_wireless() -> Result<()> {
+    let connection =
+        Connection::get_private(BusType::System).chain_err(|| ErrorKind::EnableWireless)?;
+
+    let message = Message::new_method_call(NM_BUS_NAME, NM_PATH, PROPERTIES_INTERFACE, "Set")
+        .map_err(Error::from)
+        .chain_err(|| ErrorKind::EnableWireless)?
+        .append1(NM_BUS_NAME)
+        .append1("WirelessEnabled")
+        .append1(Variant(true));
+
+    connection
+        .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+        .chain_err(|| ErrorKind::EnableWireless)?;
+
+    Ok(())
+}
+
+/// The `WirelessCapabilities` flags of a WiFi device, if NetworkManager can
+/// tell
+pub fn wireless_capabilities(interface: &str) -> Option<u32> {
+    let result = Connection::get_private(BusType::System)
+        .chain_err(|| "Connecting to D-Bus failed")
+        .and_then(|connection| {
+            let path = device_path(&connection, interface)?;
+
+            let message =
+                Message::new_method_call(NM_BUS_NAME, path.as_str(), PROPERTIES_INTERFACE, "Get")?
+                    .append1(NM_WIRELESS_INTERFACE)
+                    .append1("WirelessCapabilities");
+
+            let reply = connection
+                .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+                .chain_err(|| "Getting WirelessCapabilities failed")?;
+
+            let capabilities = reply
+                .read1::<Variant<u32>>()
+                .map_err(|_| "Unexpected WirelessCapabilities type")?;
+
+            Ok(capabilities.0)
+        });
+
+    match result {
+        Ok(capabilities) => Some(capabilities),
+        Err(e) => {
+            debug!("Getting capabilities of {} failed: {}", interface, e);
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessPointRadio {
+    pub bssid: String,
+    /// In MHz
+    pub frequency: u32,
+}
+
+/// The BSSID and frequency of each access point, if NetworkManager can tell
+pub fn access_point_radios(paths: &[&str]) -> Vec<Option<AccessPointRadio>> {
+    let connection = match Connection::get_private(BusType::System) {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("Connecting to D-Bus failed: {}", e);
+            return vec![None; paths.len()];
+        }
+    };
+
+    paths
+        .iter()
+        .map(|path| match access_point_radio(&connection, path) {
+            Ok(radio) => Some(radio),
+            Err(e) => {
+                debug!("Getting radio of {} failed: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn access_point_radio(connection: &Connection, path: &str) -> Result<AccessPointRadio> {
+    let bssid = get_access_point_property(connection, path, "HwAddress")?
+        .read1::<Variant<String>>()
+        .map_err(|_| "Unexpected HwAddress type")?;
+
+    let frequency = get_access_point_property(connection, path, "Frequency")?
+        .read1::<Variant<u32>>()
+        .map_err(|_| "Unexpected Frequency type")?;
+
+    Ok(AccessPointRadio {
+        bssid: bssid.0,
+        frequency: frequency.0,
+    })
+}
+
+fn get_access_point_property(
+    connection: &Connection,
+    path: &str,
+    property: &str,
+) -> Result<Message> {
+    let message = Message::new_method_call(NM_BUS_NAME, path, PROPERTIES_INTERFACE, "Get")?
+        .append1(NM_ACCESS_POINT_INTERFACE)
+        .append1(property);
+
+    connection
+        .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+        .chain_err(|| format!("Getting {} failed", property))
+}
+
+/// Adds the hotspot profile without activating it, so that it starts on the
+/// right band and channel right away
+pub fn add_hotspot_connection(hotspot: &HotspotSettings) -> Result<()> {
+    let mut wireless = VariantMap::new();
+    add_variant(&mut wireless, "ssid", hotspot.ssid.as_bytes().to_vec());
+    add_variant(&mut wireless, "band", hotspot.band.to_string());
+    add_variant(&mut wireless, "hidden", false);
+    add_variant(&mut wireless, "mode", "ap".to_string());
+
+    if let Some(channel) = hotspot.channel {
+        add_variant(&mut wireless, "channel", channel);
+    }
+
+    let mut connection = VariantMap::new();
+    add_variant(&mut connection, "autoconnect", false);
+    add_variant(&mut connection, "id", hotspot.ssid.to_string());
+    add_variant(
+        &mut connection,
+        "interface-name",
+        hotspot.interface.to_string(),
+    );
+    add_variant(&mut connection, "type", "802-11-wireless".to_string());
+
+    let mut address = VariantMap::new();
+    add_variant(&mut address, "address", hotspot.gateway.to_string());
+    add_variant(&mut address, "prefix", 24_u32);
+
+    let mut ipv4 = VariantMap::new();
+    add_variant(&mut ipv4, "method", "manual".to_string());
+    add_variant(&mut ipv4, "address-data", vec![address]);
+
+    let mut settings = HashMap::new();
+
+    if let Some(passphrase) = hotspot.passphrase {
+        add_variant(
+            &mut wireless,
+            "security",
+            "802-11-wireless-security".to_string(),
+        );
+
+        let mut security = VariantMap::new();
+        add_variant(&mut security, "key-mgmt", "wpa-psk".to_string());
+        add_variant(&mut security, "psk", passphrase.to_string());
+
+        settings.insert("802-11-wireless-security".to_string(), security);
+    }
+
+    settings.insert("802-11-wireless".to_string(), wireless);
+    settings.insert("connection".to_string(), connection);
+    settings.insert("ipv4".to_string(), ipv4);
+
+    let connection =
+        Connection::get_private(BusType::System).chain_err(|| "Connecting to D-Bus failed")?;
+
+    let message = Message::new_method_call(
+        NM_BUS_NAME,
+        NM_SETTINGS_PATH,
+        NM_SETTINGS_INTERFACE,
+        "AddConnection",
+    )?
+    .append1(settings);
+
+    connection
+        .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+        .chain_err(|| "Adding the hotspot connection failed")?;
+
+    Ok(())
+}
+
+fn add_variant<V: RefArg + 'static>(map: &mut VariantMap, key: &str, value: V) {
+    map.insert(key.to_string(), Variant(Box::new(value)));
+}
+
+/// Sets the `Managed` property, the same as `nmcli device set <interface>
+/// managed yes`
+pub fn set_device_managed(interface: &str) -> Result<()> {
+    let connection = Connection::get_private(BusType::System)
+        .chain_err(|| ErrorKind::ManageDevice(interface.into()))?;
+
+    let path = device_path(&connection, interface)
+        .chain_err(|| ErrorKind::ManageDevice(interface.into()))?;
+
+    let message = Message::new_method_call(NM_BUS_NAME, path.as_str(), PROPERTIES_INTERFACE, "Set")
+        .map_err(Error::from)
+        .chain_err(|| ErrorKind::ManageDevice(interface.into()))?
+        .append1(NM_DEVICE_INTERFACE)
+        .append1("Managed")
+        .append1(Variant(true));
+
+    connection
+        .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+        .chain_err(|| ErrorKind::ManageDevice(interface.into()))?;
+
+    Ok(())
+}
+
+fn device_path(connection: &Connection, interface: &str) -> Result<String> {
+    let message =
+        Message::new_method_call(NM_BUS_NAME, NM_PATH, NM_INTERFACE, "GetDeviceByIpIface")?
+            .append1(interface);
+
+    let reply = connection
+        .send_with_reply_and_block(message, METHOD_CALL_TIMEOUT_MS)
+        .chain_err(|| ErrorKind::DeviceByInterface(interface.into()))?;
+
+    let path = reply
+        .read1::<Path>()
+        .map_err(|_| "Unexpected device path type")?;
+
+    Ok(path.to_string())
+}
+
+/// Tells the network thread when NetworkManager loses or changes its bus name,
+/// e.g. when it is restarted, and when devices are added or removed. The
+/// watcher reconnects if its own D-Bus connection is lost.
+pub fn spawn_network_manager_watcher(network_tx: Sender<NetworkCommand>) {
+    thread::spawn(move || loop {
+        match Connection::get_private(BusType::System) {
+            Ok(connection) => {
+                if watch_network_manager(&connection, &network_tx).is_err() {
+                    return;
+                }
+
+                warn!("D-Bus connection for watching NetworkManager lost. Reconnecting...");
+            }
+            Err(e) => warn!(
+                "Connecting to D-Bus for watching NetworkManager failed: {}",
+                e
+            ),
+        }
+
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
+    });
+}
+
+/// Returns an error once the network thread is gone
+fn watch_network_manager(
+    connection: &Connection,
+    network_tx: &Sender<NetworkCommand>,
+) -> ::std::result::Result<(), ()> {
+    for rule in &[
+        NAME_OWNER_CHANGED_MATCH,
+        DEVICE_ADDED_MATCH,
+        DEVICE_REMOVED_MATCH,
+    ] {
+        if let Err(e) = connection.add_match(rule) {
+            warn!("Subscribing to NetworkManager signals failed: {}", e);
+            return Ok(());
+        }
+    }
+
+    for item in connection.iter(SIGNAL_POLL_INTERVAL_MS) {
+        let signal = match item {
+            ConnectionItem::Signal(signal) => signal,
+            _ => continue,
+        };
+
+        let command = match signal.headers().3.as_deref() {
+            Some("NameOwnerChanged") => name_owner_changed(&signal),
+            Some("DeviceAdded") => Some(NetworkCommand::DeviceAdded),
+            Some("DeviceRemoved") => Some(NetworkCommand::DeviceRemoved),
+            _ => None,
+        };
+
+        if let Some(command) = command {
+            network_tx.send(command).map_err(|_| ())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn name_owner_changed(signal: &Message) -> Option<NetworkCommand> {
+    let (name, old_owner, new_owner) = signal.read3::<&str, &str, &str>().ok()?;
+
+    if name != NM_BUS_NAME || old_owner.is_empty() {
+        return None;
+    }
+
+    if new_owner.is_empty() {
+        warn!("NetworkManager left D-Bus");
+    } else {
+        warn!("NetworkManager changed its D-Bus connection");
+    }
+
+    Some(NetworkCommand::NetworkManagerRestarted)
+}
@@ -0,0 +1,136 @@
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use access::LoginRequest;
+use config::PortalConfig;
+use network::{DeviceInfo, Network, Status};
+use server::{ApiErrorBody, ConnectRequest, ConnectionAccepted};
+
+/// OpenAPI description of the `/api/v1` namespace with schemas generated
+/// from the types the handlers serialize
+pub fn openapi_document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+
+    let error = schema_for::<ApiErrorBody>(&mut gen);
+    let networks = schema_for::<Vec<Network>>(&mut gen);
+    let connect_request = schema_for::<ConnectRequest>(&mut gen);
+    let login_request = schema_for::<LoginRequest>(&mut gen);
+    let connection = schema_for::<ConnectionAccepted>(&mut gen);
+    let status = schema_for::<Status>(&mut gen);
+    let device = schema_for::<DeviceInfo>(&mut gen);
+    let config = schema_for::<PortalConfig>(&mut gen);
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "WiFi Connect API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "paths": {
+            "/networks": {
+                "get": operation(
+                    "List the WiFi networks found by the last scan",
+                    "200",
+                    &networks,
+                    &error,
+                ),
+            },
+            "/connection": {
+                "post": {
+                    "summary": "Stop the captive portal and connect to a WiFi network",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": connect_request },
+                            "application/x-www-form-urlencoded": { "schema": connect_request },
+                        },
+                    },
+                    "responses": responses("202", &connection, &error),
+                },
+            },
+            "/login": {
+                "post": {
+                    "summary": "Start a portal session with the access code",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": login_request },
+                            "application/x-www-form-urlencoded": { "schema": login_request },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Session started",
+                            "headers": {
+                                "Set-Cookie": {
+                                    "description": "Session cookie required by the other resources",
+                                    "schema": { "type": "string" },
+                                },
+                            },
+                        },
+                        "400": error_response("Invalid request", &error),
+                        "401": error_response("Wrong access code", &error),
+                        "429": error_response("Too many attempts", &error),
+                        "500": error_response("Creating the session failed", &error),
+                    },
+                },
+            },
+            "/status": {
+                "get": operation("Get the current state", "200", &status, &error),
+            },
+            "/device": {
+                "get": operation("Get the WiFi device information", "200", &device, &error),
+            },
+            "/config": {
+                "get": operation(
+                    "Get the effective configuration with secrets redacted",
+                    "200",
+                    &config,
+                    &error,
+                ),
+            },
+        },
+        "components": {
+            "schemas": gen.definitions(),
+        },
+    })
+}
+
+fn schema_for<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    let schema: Schema = gen.subschema_for::<T>();
+    ::serde_json::to_value(schema).unwrap_or(Value::Null)
+}
+
+fn operation(summary: &str, code: &str, schema: &Value, error: &Value) -> Value {
+    json!({
+        "summary": summary,
+        "responses": responses(code, schema, error),
+    })
+}
+
+fn responses(code: &str, schema: &Value, error: &Value) -> Value {
+    let mut responses = json!({
+        "400": error_response("Invalid request", error),
+        "401": error_response("Portal access code required", error),
+        "409": error_response("Device is being configured by another user", error),
+        "429": error_response("Too many attempts", error),
+        "503": error_response("Network thread busy or unavailable", error),
+    });
+
+    responses[code] = json!({
+        "description": "Success",
+        "content": { "application/json": { "schema": schema } },
+    });
+
+    responses
+}
+
+fn error_response(description: &str, error: &Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": error } },
+    })
+}
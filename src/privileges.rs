@@ -1,13 +1,104 @@
-use nix::unistd::Uid;
+use std::path::Path;
 
+use caps::{self, CapSet, Capability};
+use nix::unistd::{access, AccessFlags, Uid};
+
+use config::Config;
 use errors::*;
 
-pub fn require_root() -> Result<()> {
-    if !Uid::effective().is_root() {
+/// Needed for configuring the network and by dnsmasq for DHCP and DNS, and
+/// for binding the web server to a port below 1024
+const REQUIRED_CAPABILITIES: &[Capability] = &[
+    Capability::CAP_NET_ADMIN,
+    Capability::CAP_NET_BIND_SERVICE,
+    Capability::CAP_NET_RAW,
+];
+
+/// Root is required unless running with `--capabilities`
+pub fn require_privileges(config: &Config) -> Result<()> {
+    if Uid::effective().is_root() {
+        return Ok(());
+    }
+
+    if !config.capabilities {
         bail!(ErrorKind::RootPrivilegesRequired(
             env!("CARGO_PKG_NAME").into()
         ));
-    } else {
-        Ok(())
     }
+
+    require_capabilities()?;
+
+    require_writable_directories(config)
+}
+
+/// The capabilities are raised to the ambient set, so that dnsmasq inherits
+/// them. Threads spawned afterwards inherit them as well.
+fn require_capabilities() -> Result<()> {
+    let missing = REQUIRED_CAPABILITIES
+        .iter()
+        .filter(|capability| !caps::has_cap(None, CapSet::Effective, **capability).unwrap_or(false))
+        .map(|capability| capability.to_string())
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        bail!(ErrorKind::CapabilitiesRequired(
+            env!("CARGO_PKG_NAME").into(),
+            missing.join(", ")
+        ));
+    }
+
+    for capability in REQUIRED_CAPABILITIES {
+        caps::raise(None, CapSet::Inheritable, *capability)
+            .chain_err(|| ErrorKind::ChangeCapabilities)?;
+        caps::raise(None, CapSet::Ambient, *capability)
+            .chain_err(|| ErrorKind::ChangeCapabilities)?;
+    }
+
+    info!("Running with capabilities instead of root privileges");
+
+    Ok(())
+}
+
+/// The default directories of the control socket and of the generated TLS
+/// certificate are owned by root, so without root they have to be created
+/// beforehand, e.g. with systemd's `RuntimeDirectory=` and `StateDirectory=`
+fn require_writable_directories(config: &Config) -> Result<()> {
+    let mut directories: Vec<&Path> = config.control_socket.parent().into_iter().collect();
+
+    if let Some(ref tls) = config.tls {
+        if tls.certificate.is_none() {
+            directories.push(&tls.directory);
+        }
+    }
+
+    for directory in directories {
+        if !is_writable(directory) {
+            bail!(ErrorKind::DirectoryNotWritable(
+                directory.display().to_string()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Missing directories are created, so the nearest existing one has to be
+/// writable
+fn is_writable(directory: &Path) -> bool {
+    match directory.ancestors().find(|path| path.exists()) {
+        Some(path) => access(path, AccessFlags::W_OK).is_ok(),
+        None => false,
+    }
+}
+
+/// Capabilities are per thread, so only the calling thread and the threads it
+/// spawns afterwards lose them
+pub fn drop_thread_capabilities() -> Result<()> {
+    for set in &[CapSet::Ambient, CapSet::Effective, CapSet::Permitted] {
+        caps::clear(None, *set).chain_err(|| ErrorKind::ChangeCapabilities)?;
+    }
+
+    debug!("Dropped capabilities of the web server");
+
+    Ok(())
 }
@@ -0,0 +1,130 @@
+use std::fs;
+use std::path::Path;
+
+use network_manager::NetworkManager;
+
+use config::Config;
+use errors::*;
+use nm_dbus::enable_wireless;
+
+const RFKILL_PATH: &str = "/sys/class/rfkill";
+const NET_PATH: &str = "/sys/class/net";
+
+struct RfkillSwitch {
+    name: String,
+    soft_path: String,
+    soft: bool,
+    hard: bool,
+    /// `None` for platform switches, e.g. of a laptop's WiFi key
+    phy: Option<String>,
+}
+
+/// Creating the hotspot fails without much of a hint if WiFi is blocked, so
+/// the blocks are checked up front for the switches of the used devices.
+/// Soft blocks are lifted only with `--unblock-radio`, a hard block needs a
+/// physical switch.
+pub fn check_radio(config: &Config, interfaces: &[&str]) -> Result<()> {
+    let phys: Vec<Option<String>> = interfaces.iter().map(|interface| phy(interface)).collect();
+
+    for switch in rfkill_switches() {
+        if !blocks_devices(&switch, &phys) {
+            debug!("Skipping rfkill switch {} of another device", switch.name);
+            continue;
+        }
+
+        if switch.hard {
+            bail!(ErrorKind::RadioHardBlocked(switch.name));
+        }
+
+        if switch.soft {
+            if !config.unblock_radio {
+                bail!(ErrorKind::RadioSoftBlocked(switch.name));
+            }
+
+            info!("Unblocking rfkill switch {}", switch.name);
+
+            fs::write(&switch.soft_path, "0")
+                .chain_err(|| ErrorKind::RadioUnblock(switch.name.clone()))?;
+        }
+    }
+
+    let manager = NetworkManager::new();
+
+    if !manager.is_wireless_enabled()? {
+        if !config.unblock_radio {
+            bail!(ErrorKind::WirelessDisabled);
+        }
+
+        info!("Enabling WiFi in NetworkManager");
+
+        enable_wireless()?;
+    }
+
+    Ok(())
+}
+
+/// Only WiFi switches are of interest. Systems without rfkill support have
+/// nothing to check.
+fn rfkill_switches() -> Vec<RfkillSwitch> {
+    let entries = match fs::read_dir(RFKILL_PATH) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Reading {} failed: {}", RFKILL_PATH, e);
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| read_attribute(path, "type").as_deref() == Some("wlan"))
+        .map(|path| RfkillSwitch {
+            name: read_attribute(&path, "name").unwrap_or_else(|| path.display().to_string()),
+            soft_path: path.join("soft").display().to_string(),
+            soft: read_attribute(&path, "soft").as_deref() == Some("1"),
+            hard: read_attribute(&path, "hard").as_deref() == Some("1"),
+            phy: switch_phy(&path),
+        })
+        .collect()
+}
+
+/// Platform switches may block all devices, as may any switch if the phy of
+/// a device is unknown
+fn blocks_devices(switch: &RfkillSwitch, phys: &[Option<String>]) -> bool {
+    let switch_phy = match switch.phy {
+        Some(ref phy) => phy,
+        None => return true,
+    };
+
+    phys.iter().any(|phy| match *phy {
+        Some(ref phy) => phy == switch_phy,
+        None => true,
+    })
+}
+
+/// Switches of a phy are children of its `ieee80211/phyN` sysfs device
+fn switch_phy(path: &Path) -> Option<String> {
+    let device = fs::canonicalize(path.join("device")).ok()?;
+
+    if device.parent()?.file_name()? != "ieee80211" {
+        return None;
+    }
+
+    device.file_name()?.to_str().map(String::from)
+}
+
+fn phy(interface: &str) -> Option<String> {
+    let path = Path::new(NET_PATH).join(interface).join("phy80211");
+
+    fs::canonicalize(path)
+        .ok()?
+        .file_name()?
+        .to_str()
+        .map(String::from)
+}
+
+fn read_attribute(path: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(path.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
@@ -0,0 +1,71 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use errors::*;
+
+/// The kernel applies a new domain asynchronously
+const REGULATORY_DOMAIN_TIMEOUT_SECS: u64 = 5;
+
+const REGULATORY_DOMAIN_POLL_INTERVAL_MS: u64 = 500;
+
+/// Sets the regulatory domain with `--country`, so that scanning and the
+/// hotspot may use the channels allowed in that country, and logs the
+/// effective one
+pub fn init_regulatory_domain(config: &Config) -> Result<()> {
+    if let Some(ref country) = config.country {
+        set_regulatory_domain(country)?;
+    }
+
+    match regulatory_domain() {
+        Some(domain) => info!("Regulatory domain: {}", domain),
+        None => warn!("Cannot get the regulatory domain"),
+    }
+
+    Ok(())
+}
+
+fn set_regulatory_domain(country: &str) -> Result<()> {
+    info!("Setting regulatory domain {}...", country);
+
+    let status = Command::new("iw")
+        .args(["reg", "set", country])
+        .status()
+        .chain_err(|| ErrorKind::SetRegulatoryDomain(country.into()))?;
+
+    if !status.success() {
+        bail!(ErrorKind::SetRegulatoryDomain(country.into()));
+    }
+
+    let started = Instant::now();
+
+    while regulatory_domain().as_deref() != Some(country) {
+        if started.elapsed() >= Duration::from_secs(REGULATORY_DOMAIN_TIMEOUT_SECS) {
+            warn!(
+                "Regulatory domain {} not applied, e.g. the driver enforces its own",
+                country
+            );
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(REGULATORY_DOMAIN_POLL_INTERVAL_MS));
+    }
+
+    Ok(())
+}
+
+/// The global domain from `iw reg get`, e.g. `country DE: DFS-ETSI`
+pub fn regulatory_domain() -> Option<String> {
+    let output = Command::new("iw").args(["reg", "get"]).output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("country "))
+        .and_then(|rest| rest.split(':').next())
+        .map(String::from)
+}
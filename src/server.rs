@@ -1,49 +1,146 @@
 use std::error::Error as StdError;
 use std::fmt;
 use std::net::Ipv4Addr;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
 
-use iron::modifiers::Redirect;
+use hyper::net::{HttpListener, HttpsListener};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::modifiers::{Header, Redirect};
 use iron::prelude::*;
 use iron::{
-    headers, status, typemap, AfterMiddleware, Iron, IronError, IronResult, Request, Response, Url,
+    headers, status, typemap, AfterMiddleware, Handler, Iron, IronError, IronResult, Protocol,
+    Request, Response, Url,
 };
 use iron_cors::CorsMiddleware;
 use mount::Mount;
 use params::{FromValue, Params};
-use path::PathBuf;
 use persistent::Write;
 use router::Router;
+use serde::Serialize;
 use serde_json;
 use staticfile::Static;
 
+use access::{require_access, AccessControl, Login};
+use config::{PortalConfig, SharedConfig, TlsConfig};
 use errors::*;
+use events::{EventBus, EventStream};
 use exit::{exit, ExitResult};
-use network::{NetworkCommand, NetworkCommandResponse};
+use logger::{recent_logs, timestamp, LogEntry};
+use metrics::{Metrics, MetricsHandler, TrackClients};
+use network::{
+    reply_channel, NetworkCommand, NetworkCommandResponse, NetworkDiagnostics, ReplyTx, State,
+};
+use openapi::openapi_document;
+use privileges::drop_thread_capabilities;
+use session_lock::{RateLimit, RequireSessionLock, SessionLock};
+use systemd::{notify_ready, take_activated_listener};
+use tls::TlsServer;
+use validation::{validate_ssid, ValidationError};
+
+/// How long a request waits for the network thread, which may be busy
+/// connecting to a network, before failing with `503 Service Unavailable`
+const NETWORK_COMMAND_TIMEOUT_SECS: u64 = 15;
 
 struct RequestSharedState {
     gateway: Ipv4Addr,
-    server_rx: Receiver<NetworkCommandResponse>,
     network_tx: Sender<NetworkCommand>,
-    exit_tx: Sender<ExitResult>,
+    config: SharedConfig,
+    events: EventBus,
 }
 
 impl typemap::Key for RequestSharedState {
     type Value = RequestSharedState;
 }
 
+/// Error responses have a JSON body with a stable machine readable code:
+/// `{"error": {"code": "invalid_passphrase", "message": "..."}}`
 #[derive(Debug)]
-struct StringError(String);
+pub struct ApiError {
+    status: status::Status,
+    code: &'static str,
+    message: String,
+}
 
-impl fmt::Display for StringError {
+impl ApiError {
+    pub fn new(status: status::Status, code: &'static str, message: &str) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        write!(f, "{}: {}", self.code, self.message)
     }
 }
 
-impl StdError for StringError {
+impl StdError for ApiError {
     fn description(&self) -> &str {
-        &self.0
+        &self.message
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "Error")]
+pub struct ApiErrorBody<'a> {
+    error: ApiErrorDetails<'a>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "ErrorDetails")]
+struct ApiErrorDetails<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+/// Body of `POST /api/v1/connection`, either as JSON or form encoded
+#[derive(Serialize, JsonSchema)]
+pub struct ConnectRequest {
+    ssid: String,
+    identity: Option<String>,
+    passphrase: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ConnectionAccepted {
+    ssid: String,
+    state: State,
+}
+
+/// Downloaded from `GET /diagnostics` when a device fails to connect on-site
+#[derive(Serialize)]
+struct Diagnostics {
+    generated_at: String,
+    version: &'static str,
+    config: PortalConfig,
+    network: NetworkDiagnostics,
+    logs: Vec<LogEntry>,
+}
+
+impl From<ApiError> for IronError {
+    fn from(e: ApiError) -> IronError {
+        let body = serde_json::to_string(&ApiErrorBody {
+            error: ApiErrorDetails {
+                code: e.code,
+                message: &e.message,
+            },
+        })
+        .unwrap_or_default();
+
+        let status = e.status;
+
+        IronError::new(e, (status, body, Header(headers::ContentType::json())))
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(e: ValidationError) -> ApiError {
+        ApiError::new(status::BadRequest, e.code, &e.message)
     }
 }
 
@@ -51,36 +148,43 @@ macro_rules! get_request_ref {
     ($req:ident, $ty:ty, $err:expr) => {
         match $req.get_ref::<$ty>() {
             Ok(val) => val,
-            Err(err) => {
+            Err(_) => {
                 error!($err);
-                return Err(IronError::new(err, status::InternalServerError));
+                return Err(
+                    ApiError::new(status::InternalServerError, "internal_error", $err).into(),
+                );
             }
         }
     };
 }
 
 macro_rules! get_param {
+    ($params:ident, $param:expr, $ty:ty) => {
+        match get_optional_param!($params, $param, $ty) {
+            Some(value) => value,
+            None => {
+                let message = format!("'{}' not found in request params", $param);
+                return Err(
+                    ApiError::new(status::BadRequest, "missing_parameter", &message).into(),
+                );
+            }
+        }
+    };
+}
+
+macro_rules! get_optional_param {
     ($params:ident, $param:expr, $ty:ty) => {
         match $params.get($param) {
             Some(value) => match <$ty as FromValue>::from_value(value) {
-                Some(converted) => converted,
+                Some(converted) => Some(converted),
                 None => {
-                    let err = format!("Unexpected type for '{}'", $param);
-                    error!("{}", err);
-                    return Err(IronError::new(
-                        StringError(err),
-                        status::InternalServerError,
-                    ));
+                    let message = format!("Unexpected type for '{}'", $param);
+                    return Err(
+                        ApiError::new(status::BadRequest, "invalid_parameter", &message).into(),
+                    );
                 }
             },
-            None => {
-                let err = format!("'{}' not found in request params: {:?}", $param, $params);
-                error!("{}", err);
-                return Err(IronError::new(
-                    StringError(err),
-                    status::InternalServerError,
-                ));
-            }
+            None => None,
         }
     };
 }
@@ -98,20 +202,65 @@ macro_rules! get_request_state {
     };
 }
 
-fn exit_with_error<E>(state: &RequestSharedState, e: E, e_kind: ErrorKind) -> IronResult<Response>
+/// Sends a command to the network thread and waits for the response on the
+/// reply channel created for this particular request
+fn send_network_command<F>(req: &mut Request, command: F) -> IronResult<NetworkCommandResponse>
 where
-    E: ::std::error::Error + Send + 'static,
+    F: FnOnce(ReplyTx) -> NetworkCommand,
 {
-    let description = e_kind.description().into();
-    let err = Err::<Response, E>(e).chain_err(|| e_kind);
-    exit(&state.exit_tx, err.unwrap_err());
-    Err(IronError::new(
-        StringError(description),
-        status::InternalServerError,
-    ))
+    let network_tx = get_request_state!(req).network_tx.clone();
+
+    let (reply_tx, reply_rx) = reply_channel();
+
+    if network_tx.send(command(reply_tx)).is_err() {
+        return Err(service_unavailable("Network thread is not running"));
+    }
+
+    match reply_rx.recv_timeout(Duration::from_secs(NETWORK_COMMAND_TIMEOUT_SECS)) {
+        Ok(response) => Ok(response),
+        Err(RecvTimeoutError::Timeout) => Err(service_unavailable(
+            "Timed out waiting for the network thread",
+        )),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(service_unavailable("Network thread dropped the request"))
+        }
+    }
 }
 
-struct RedirectMiddleware;
+fn service_unavailable(description: &str) -> IronError {
+    warn!("{}", description);
+    ApiError::new(
+        status::ServiceUnavailable,
+        "service_unavailable",
+        description,
+    )
+    .into()
+}
+
+fn json_response<T: Serialize>(status: status::Status, value: &T) -> IronResult<Response> {
+    match serde_json::to_string(value) {
+        Ok(json) => Ok(Response::with((
+            status,
+            json,
+            Header(headers::ContentType::json()),
+        ))),
+        Err(e) => {
+            let description = format!("Serializing response failed: {}", e);
+            error!("{}", description);
+            Err(ApiError::new(status::InternalServerError, "internal_error", &description).into())
+        }
+    }
+}
+
+fn unexpected_response() -> IronError {
+    let description = "Unexpected network command response";
+    error!("{}", description);
+    ApiError::new(status::InternalServerError, "internal_error", description).into()
+}
+
+struct RedirectMiddleware {
+    portal_url: Url,
+}
 
 impl AfterMiddleware for RedirectMiddleware {
     fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
@@ -122,7 +271,7 @@ impl AfterMiddleware for RedirectMiddleware {
 
         if let Some(host) = req.headers.get::<headers::Host>() {
             if host.hostname != gateway {
-                let url = Url::parse(&format!("http://{}/", gateway)).unwrap();
+                let url = self.portal_url.clone();
                 return Ok(Response::with((status::Found, Redirect(url))));
             }
         }
@@ -131,100 +280,484 @@ impl AfterMiddleware for RedirectMiddleware {
     }
 }
 
+/// Redirects every plain HTTP request to the HTTPS portal, so that captive
+/// portal detection still works while credentials are only sent encrypted
+struct CaptiveRedirect {
+    portal_url: Url,
+}
+
+impl Handler for CaptiveRedirect {
+    fn handle(&self, _: &mut Request) -> IronResult<Response> {
+        let url = self.portal_url.clone();
+        Ok(Response::with((status::Found, Redirect(url))))
+    }
+}
+
+fn get_portal_url(gateway: Ipv4Addr, tls: &Option<TlsConfig>) -> Url {
+    let url = match *tls {
+        Some(ref tls) if tls.listening_port != 443 => {
+            format!("https://{}:{}/", gateway, tls.listening_port)
+        }
+        Some(_) => format!("https://{}/", gateway),
+        None => format!("http://{}/", gateway),
+    };
+
+    Url::parse(&url).unwrap()
+}
+
+/// Serves the UI directory of the current configuration, which may change on
+/// reload
+struct UiDirectory {
+    config: SharedConfig,
+    subdirectory: Option<&'static str>,
+}
+
+impl Handler for UiDirectory {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let mut root = self.config.read().unwrap().ui_directory.clone();
+
+        if let Some(subdirectory) = self.subdirectory {
+            root.push(subdirectory);
+        }
+
+        Static::new(root).handle(req)
+    }
+}
+
+/// Middleware shared by the routes that need them. The legacy routes and
+/// their `/api/v1` counterparts share the same session lock and rate limit.
+struct RouteGuards {
+    access: Option<Arc<AccessControl>>,
+    session_lock: Arc<SessionLock>,
+    rate_limit: RateLimit,
+}
+
+impl RouteGuards {
+    fn protected<H: Handler>(&self, handler: H) -> Chain {
+        require_access(handler, &self.access)
+    }
+
+    fn configuration<H: Handler>(&self, handler: H) -> Chain {
+        let mut chain = self.protected(handler);
+        chain.link_before(RequireSessionLock::new(&self.session_lock));
+        chain
+    }
+
+    fn connection<H: Handler>(&self, handler: H) -> Chain {
+        let mut chain = self.configuration(handler);
+        chain.link_before(self.rate_limit.clone());
+        chain
+    }
+}
+
 pub fn start_server(
-    gateway: Ipv4Addr,
-    listening_port: u16,
-    server_rx: Receiver<NetworkCommandResponse>,
+    shared_config: &SharedConfig,
     network_tx: Sender<NetworkCommand>,
     exit_tx: Sender<ExitResult>,
-    ui_directory: &PathBuf,
+    access_code: Option<String>,
+    events: EventBus,
+    metrics: Arc<Metrics>,
 ) {
-    let exit_tx_clone = exit_tx.clone();
+    let config = shared_config.read().unwrap().clone();
+    let gateway = config.gateway;
+    let listening_port = config.listening_port;
+    let tls = config.tls.clone();
     let gateway_clone = gateway;
     let request_state = RequestSharedState {
         gateway,
-        server_rx,
         network_tx,
-        exit_tx,
+        config: shared_config.clone(),
+        events,
+    };
+
+    let ui_directory = |subdirectory| UiDirectory {
+        config: shared_config.clone(),
+        subdirectory,
+    };
+
+    let access = access_code.map(|code| Arc::new(AccessControl::new(code, tls.is_some())));
+
+    let guards = RouteGuards {
+        access: access.clone(),
+        session_lock: Arc::new(SessionLock::new()),
+        rate_limit: RateLimit::new(),
     };
 
     let mut router = Router::new();
-    router.get("/", Static::new(ui_directory), "index");
-    router.get("/networks", networks, "networks");
-    router.post("/connect", connect, "connect");
+    router.get("/", ui_directory(None), "index");
+
+    // Legacy routes kept as aliases of the `/api/v1` resources
+    router.get("/networks", guards.configuration(networks), "networks");
+    router.post("/connect", guards.connection(connect), "connect");
+
+    router.get(
+        "/api/v1/networks",
+        guards.configuration(networks),
+        "api_v1_networks",
+    );
+    router.post(
+        "/api/v1/connection",
+        guards.connection(create_connection),
+        "api_v1_connection",
+    );
+    router.get("/api/v1/status", guards.protected(status), "api_v1_status");
+    router.get("/api/v1/device", guards.protected(device), "api_v1_device");
+    router.get(
+        "/api/v1/config",
+        guards.protected(portal_config),
+        "api_v1_config",
+    );
+    router.get("/events", guards.protected(event_stream), "events");
+    router.get("/logs", guards.protected(logs), "logs");
+    router.get("/diagnostics", guards.protected(diagnostics), "diagnostics");
+    if config.portal_metrics {
+        router.get(
+            "/metrics",
+            guards.protected(MetricsHandler {
+                metrics: metrics.clone(),
+            }),
+            "metrics",
+        );
+    }
+
+    router.get("/api/v1/openapi.json", openapi, "api_v1_openapi");
+
+    if let Some(ref access) = access {
+        router.post(
+            "/login",
+            Login {
+                access: access.clone(),
+            },
+            "login",
+        );
+        router.post(
+            "/api/v1/login",
+            Login {
+                access: access.clone(),
+            },
+            "api_v1_login",
+        );
+    }
 
     let mut assets = Mount::new();
     assets.mount("/", router);
-    assets.mount("/static", Static::new(ui_directory.join("static")));
-    assets.mount("/css", Static::new(ui_directory.join("css")));
-    assets.mount("/img", Static::new(ui_directory.join("img")));
-    assets.mount("/js", Static::new(ui_directory.join("js")));
+    assets.mount("/static", ui_directory(Some("static")));
+    assets.mount("/css", ui_directory(Some("css")));
+    assets.mount("/img", ui_directory(Some("img")));
+    assets.mount("/js", ui_directory(Some("js")));
 
     let cors_middleware = CorsMiddleware::with_allow_any();
 
+    let portal_url = get_portal_url(gateway_clone, &tls);
+
     let mut chain = Chain::new(assets);
     chain.link(Write::<RequestSharedState>::both(request_state));
-    chain.link_after(RedirectMiddleware);
+    chain.link_before(TrackClients { metrics });
+    chain.link_after(RedirectMiddleware {
+        portal_url: portal_url.clone(),
+    });
     chain.link_around(cors_middleware);
 
     let address = format!("{}:{}", gateway_clone, listening_port);
 
-    info!("Starting HTTP server on {}", &address);
+    let capabilities = config.capabilities;
+
+    let tls = match tls {
+        Some(tls) => tls,
+        None => {
+            info!("Starting HTTP server on {}", &address);
+
+            let listener = match http_listener(&address) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    exit(
+                        &exit_tx,
+                        ErrorKind::StartHTTPServer(address, e.to_string()).into(),
+                    );
+                    return;
+                }
+            };
+
+            if !drop_server_capabilities(capabilities, &exit_tx) {
+                return;
+            }
+
+            notify_ready();
+
+            if let Err(e) = Iron::new(chain).listen(listener, Protocol::http()) {
+                exit(
+                    &exit_tx,
+                    ErrorKind::StartHTTPServer(address, e.to_string()).into(),
+                );
+            }
+
+            return;
+        }
+    };
+
+    let tls_server = match TlsServer::new(&tls, &gateway_clone) {
+        Ok(tls_server) => tls_server,
+        Err(e) => {
+            exit(&exit_tx, e);
+            return;
+        }
+    };
+
+    let tls_address = format!("{}:{}", gateway_clone, tls.listening_port);
+
+    info!("Starting HTTPS server on {}", &tls_address);
+
+    let tls_listener = match HttpsListener::new(&tls_address, tls_server) {
+        Ok(listener) => listener,
+        Err(e) => {
+            exit(
+                &exit_tx,
+                ErrorKind::StartHTTPServer(tls_address, e.to_string()).into(),
+            );
+            return;
+        }
+    };
+
+    info!("Starting HTTP redirect server on {}", &address);
+
+    let listener = match http_listener(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            exit(
+                &exit_tx,
+                ErrorKind::StartHTTPServer(address, e.to_string()).into(),
+            );
+            return;
+        }
+    };
+
+    if !drop_server_capabilities(capabilities, &exit_tx) {
+        return;
+    }
+
+    // The hotspot and dnsmasq are already running when the server is started
+    notify_ready();
+
+    // The HTTPS server is running as long as the listening guard is alive
+    let _listening = match Iron::new(chain).listen(tls_listener, Protocol::https()) {
+        Ok(listening) => listening,
+        Err(e) => {
+            exit(
+                &exit_tx,
+                ErrorKind::StartHTTPServer(tls_address, e.to_string()).into(),
+            );
+            return;
+        }
+    };
 
-    if let Err(e) = Iron::new(chain).http(&address) {
+    if let Err(e) = Iron::new(CaptiveRedirect { portal_url }).listen(listener, Protocol::http()) {
         exit(
-            &exit_tx_clone,
+            &exit_tx,
             ErrorKind::StartHTTPServer(address, e.to_string()).into(),
         );
     }
 }
 
-fn networks(req: &mut Request) -> IronResult<Response> {
-    info!("User connected to the captive portal");
+/// The socket passed by systemd with `--socket-activation` replaces the plain
+/// HTTP listener
+fn http_listener(address: &str) -> ::hyper::Result<HttpListener> {
+    match take_activated_listener() {
+        Some(listener) => {
+            info!("Using the socket passed by systemd instead of {}", address);
+            Ok(listener)
+        }
+        None => HttpListener::new(address),
+    }
+}
 
-    let request_state = get_request_state!(req);
+/// Once the listeners are bound the server threads need no capabilities. The
+/// threads handling requests inherit them from this thread.
+fn drop_server_capabilities(capabilities: bool, exit_tx: &Sender<ExitResult>) -> bool {
+    if !capabilities {
+        return true;
+    }
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::Activate) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandActivate);
+    match drop_thread_capabilities() {
+        Ok(()) => true,
+        Err(e) => {
+            exit(exit_tx, e);
+            false
+        }
     }
+}
+
+fn networks(req: &mut Request) -> IronResult<Response> {
+    info!("User connected to the captive portal");
 
-    let networks = match request_state.server_rx.recv() {
-        Ok(result) => match result {
+    let networks =
+        match send_network_command(req, |reply_tx| NetworkCommand::Activate { reply_tx })? {
             NetworkCommandResponse::Networks(networks) => networks,
+            _ => return Err(unexpected_response()),
+        };
+
+    json_response(status::Ok, &networks)
+}
+
+/// Legacy `/connect` replies with an empty `200 OK`
+fn connect(req: &mut Request) -> IronResult<Response> {
+    request_connection(req)?;
+
+    Ok(Response::with(status::Ok))
+}
+
+fn create_connection(req: &mut Request) -> IronResult<Response> {
+    let ssid = request_connection(req)?;
+
+    json_response(
+        status::Accepted,
+        &ConnectionAccepted {
+            ssid,
+            state: State::Connecting,
         },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
-    };
+    )
+}
 
-    let access_points_json = match serde_json::to_string(&networks) {
-        Ok(json) => json,
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
+/// Both `application/json` and form encoded bodies are accepted. Returns the
+/// SSID once the network thread accepted the credentials.
+fn request_connection(req: &mut Request) -> IronResult<String> {
+    check_connect_content_type(req)?;
+
+    let request = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                let message = format!("Cannot parse request body: {}", e);
+                return Err(ApiError::new(status::BadRequest, "malformed_body", &message).into());
+            }
+        };
+        ConnectRequest {
+            ssid: get_param!(params, "ssid", String),
+            identity: get_optional_param!(params, "identity", String),
+            passphrase: get_optional_param!(params, "passphrase", String),
+        }
     };
 
-    Ok(Response::with((status::Ok, access_points_json)))
+    validate_ssid(&request.ssid).map_err(ApiError::from)?;
+
+    debug!(
+        "Incoming `connect` to access point `{}` request",
+        request.ssid
+    );
+
+    let ssid = request.ssid.clone();
+
+    let response = send_network_command(req, |reply_tx| NetworkCommand::Connect {
+        ssid: request.ssid,
+        identity: request.identity.unwrap_or_default(),
+        passphrase: request.passphrase.unwrap_or_default(),
+        reply_tx,
+    })?;
+
+    match response {
+        NetworkCommandResponse::Connecting => Ok(ssid),
+        NetworkCommandResponse::InvalidCredentials(e) => {
+            debug!("Rejecting credentials: {}", e.message);
+            Err(ApiError::from(e).into())
+        }
+        NetworkCommandResponse::Failed(reason) => {
+            Err(ApiError::new(status::Conflict, "portal_not_running", &reason).into())
+        }
+        _ => Err(unexpected_response()),
+    }
 }
 
-fn connect(req: &mut Request) -> IronResult<Response> {
-    let (ssid, identity, passphrase) = {
-        let params = get_request_ref!(req, Params, "Getting request params failed");
-        let ssid = get_param!(params, "ssid", String);
-        let identity = get_param!(params, "identity", String);
-        let passphrase = get_param!(params, "passphrase", String);
-        (ssid, identity, passphrase)
+fn status(req: &mut Request) -> IronResult<Response> {
+    match send_network_command(req, |reply_tx| NetworkCommand::Status { reply_tx })? {
+        NetworkCommandResponse::Status(current) => json_response(status::Ok, &current),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn device(req: &mut Request) -> IronResult<Response> {
+    match send_network_command(req, |reply_tx| NetworkCommand::Device { reply_tx })? {
+        NetworkCommandResponse::Device(device) => json_response(status::Ok, &device),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn portal_config(req: &mut Request) -> IronResult<Response> {
+    let portal_config = current_portal_config(req)?;
+
+    json_response(status::Ok, &portal_config)
+}
+
+/// Server-Sent Events stream. The connection occupies a server thread until
+/// the client disconnects.
+fn event_stream(req: &mut Request) -> IronResult<Response> {
+    let stream = EventStream::new(&get_request_state!(req).events);
+
+    let mut response = Response::with((
+        status::Ok,
+        Header(headers::ContentType(Mime(
+            TopLevel::Text,
+            SubLevel::EventStream,
+            vec![],
+        ))),
+        Header(headers::CacheControl(vec![
+            headers::CacheDirective::NoCache,
+        ])),
+    ));
+    response.body = Some(Box::new(stream));
+
+    Ok(response)
+}
+
+fn current_portal_config(req: &mut Request) -> IronResult<PortalConfig> {
+    let config = get_request_state!(req).config.clone();
+    let portal_config = PortalConfig::from(&*config.read().unwrap());
+    Ok(portal_config)
+}
+
+fn logs(_: &mut Request) -> IronResult<Response> {
+    json_response(status::Ok, &recent_logs())
+}
+
+fn diagnostics(req: &mut Request) -> IronResult<Response> {
+    let network =
+        match send_network_command(req, |reply_tx| NetworkCommand::Diagnostics { reply_tx })? {
+            NetworkCommandResponse::Diagnostics(network) => network,
+            _ => return Err(unexpected_response()),
+        };
+
+    let bundle = Diagnostics {
+        generated_at: timestamp(),
+        version: env!("CARGO_PKG_VERSION"),
+        config: current_portal_config(req)?,
+        network,
+        logs: recent_logs(),
     };
 
-    debug!("Incoming `connect` to access point `{}` request", ssid);
+    let mut response = json_response(status::Ok, &bundle)?;
+    response.headers.set_raw(
+        "Content-Disposition",
+        vec![b"attachment; filename=\"wifi-connect-diagnostics.json\"".to_vec()],
+    );
 
-    let request_state = get_request_state!(req);
+    Ok(response)
+}
 
-    let command = NetworkCommand::Connect {
-        ssid,
-        identity,
-        passphrase,
+fn openapi(_: &mut Request) -> IronResult<Response> {
+    json_response(status::Ok, &openapi_document())
+}
+
+fn check_connect_content_type(req: &Request) -> IronResult<()> {
+    let content_type = match req.headers.get::<headers::ContentType>() {
+        Some(content_type) => content_type,
+        None => return Ok(()),
     };
 
-    if let Err(e) = request_state.network_tx.send(command) {
-        exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandConnect)
-    } else {
-        Ok(Response::with(status::Ok))
+    match (content_type.0).1.as_str() {
+        "json" | "x-www-form-urlencoded" | "form-data" => Ok(()),
+        _ => Err(ApiError::new(
+            status::UnsupportedMediaType,
+            "unsupported_media_type",
+            "Expected an application/json or form encoded body",
+        )
+        .into()),
     }
 }
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::{status, BeforeMiddleware, IronResult, Request};
+
+use server::ApiError;
+
+const SESSION_IDLE_TIMEOUT_SECS: u64 = 120;
+const CONNECT_ATTEMPTS_ALLOWED: usize = 3;
+const CONNECT_ATTEMPTS_WINDOW_SECS: u64 = 60;
+
+struct Owner {
+    client: IpAddr,
+    idle_until: Instant,
+}
+
+/// Only one client at a time may configure the device. The client holding
+/// the lock keeps it while active and loses it after a period of inactivity.
+pub struct SessionLock {
+    owner: Mutex<Option<Owner>>,
+}
+
+impl SessionLock {
+    pub fn new() -> Self {
+        SessionLock {
+            owner: Mutex::new(None),
+        }
+    }
+
+    fn acquire(&self, client: IpAddr) -> bool {
+        let mut owner = self.owner.lock().unwrap();
+
+        if let Some(ref current) = *owner {
+            if current.client != client && Instant::now() < current.idle_until {
+                return false;
+            }
+
+            if current.client != client {
+                info!("Configuration session of {} expired", current.client);
+            }
+        }
+
+        if owner.as_ref().map(|o| o.client) != Some(client) {
+            info!("Configuration session started by {}", client);
+        }
+
+        *owner = Some(Owner {
+            client,
+            idle_until: Instant::now() + Duration::from_secs(SESSION_IDLE_TIMEOUT_SECS),
+        });
+
+        true
+    }
+}
+
+pub struct RequireSessionLock {
+    lock: Arc<SessionLock>,
+}
+
+impl RequireSessionLock {
+    pub fn new(lock: &Arc<SessionLock>) -> Self {
+        RequireSessionLock { lock: lock.clone() }
+    }
+}
+
+impl BeforeMiddleware for RequireSessionLock {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let client = req.remote_addr.ip();
+
+        if self.lock.acquire(client) {
+            Ok(())
+        } else {
+            debug!("Rejecting {} - configuration session in progress", client);
+            Err(ApiError::new(
+                status::Conflict,
+                "configuration_in_progress",
+                "Device is being configured by another user",
+            )
+            .into())
+        }
+    }
+}
+
+/// Limits the number of connect attempts per client in a sliding window.
+/// Clones share the attempts, so that the limit applies across routes.
+#[derive(Clone)]
+pub struct RateLimit {
+    attempts: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        RateLimit {
+            attempts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Records an attempt of the client unless it is over the limit. The
+    /// attempts are kept as the instants they leave the window.
+    fn attempt(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+
+        let mut attempts = self.attempts.lock().unwrap();
+
+        attempts.retain(|_, expiries| {
+            expiries.retain(|expiry| now < *expiry);
+            !expiries.is_empty()
+        });
+
+        let expiries = attempts.entry(client).or_default();
+
+        if expiries.len() >= CONNECT_ATTEMPTS_ALLOWED {
+            return false;
+        }
+
+        expiries.push_back(now + Duration::from_secs(CONNECT_ATTEMPTS_WINDOW_SECS));
+
+        true
+    }
+}
+
+impl BeforeMiddleware for RateLimit {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let client = req.remote_addr.ip();
+
+        if self.attempt(client) {
+            Ok(())
+        } else {
+            warn!("Too many connect attempts from {}", client);
+            Err(ApiError::new(
+                status::TooManyRequests,
+                "too_many_attempts",
+                "Too many connect attempts. Please try again later",
+            )
+            .into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 42, last])
+    }
+
+    fn expire_owner(lock: &SessionLock) {
+        if let Some(ref mut owner) = *lock.owner.lock().unwrap() {
+            owner.idle_until = Instant::now();
+        }
+    }
+
+    #[test]
+    fn session_lock_is_held_by_first_client() {
+        let lock = SessionLock::new();
+
+        assert!(lock.acquire(client(2)));
+        assert!(lock.acquire(client(2)));
+        assert!(!lock.acquire(client(3)));
+    }
+
+    #[test]
+    fn session_lock_is_released_when_idle() {
+        let lock = SessionLock::new();
+
+        assert!(lock.acquire(client(2)));
+        expire_owner(&lock);
+
+        assert!(lock.acquire(client(3)));
+        assert!(!lock.acquire(client(2)));
+    }
+
+    #[test]
+    fn rate_limit_rejects_attempts_over_the_limit() {
+        let limit = RateLimit::new();
+
+        for _ in 0..CONNECT_ATTEMPTS_ALLOWED {
+            assert!(limit.attempt(client(2)));
+        }
+
+        assert!(!limit.attempt(client(2)));
+        assert!(limit.attempt(client(3)));
+    }
+
+    #[test]
+    fn rate_limit_is_shared_by_clones() {
+        let limit = RateLimit::new();
+        let clone = limit.clone();
+
+        for _ in 0..CONNECT_ATTEMPTS_ALLOWED {
+            assert!(clone.attempt(client(2)));
+        }
+
+        assert!(!limit.attempt(client(2)));
+    }
+
+    #[test]
+    fn rate_limit_forgets_attempts_leaving_the_window() {
+        let limit = RateLimit::new();
+
+        for _ in 0..CONNECT_ATTEMPTS_ALLOWED {
+            assert!(limit.attempt(client(2)));
+        }
+
+        for expiry in limit
+            .attempts
+            .lock()
+            .unwrap()
+            .values_mut()
+            .flat_map(|e| e.iter_mut())
+        {
+            *expiry = Instant::now();
+        }
+
+        assert!(limit.attempt(client(2)));
+        assert_eq!(limit.attempts.lock().unwrap()[&client(2)].len(), 1);
+    }
+}
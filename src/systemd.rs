@@ -0,0 +1,99 @@
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::net::HttpListener;
+use sd_notify::{self, NotifyState};
+
+/// Socket passed by systemd with socket activation, taken by the web server
+static ACTIVATED_SOCKET: Mutex<Option<RawFd>> = Mutex::new(None);
+
+/// When the network command loop started handling the current command, or
+/// last made progress in a long wait. `None` while it waits for commands.
+static BUSY_SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Takes the socket passed by systemd, if any. It has to be called before
+/// spawning threads, as it unsets the `LISTEN_FDS` environment variable.
+pub fn init_socket_activation() {
+    let fd = match sd_notify::listen_fds() {
+        Ok(mut fds) => fds.next(),
+        Err(e) => {
+            warn!("Getting sockets passed by systemd failed: {}", e);
+            None
+        }
+    };
+
+    match fd {
+        Some(fd) => debug!("Using socket {} passed by systemd", fd),
+        None => warn!("Socket activation enabled, but systemd passed no socket"),
+    }
+
+    *ACTIVATED_SOCKET.lock().unwrap() = fd;
+}
+
+/// The socket is used by the first web server only. Another one started
+/// after a reload binds its own address.
+pub fn take_activated_listener() -> Option<HttpListener> {
+    ACTIVATED_SOCKET
+        .lock()
+        .unwrap()
+        .take()
+        .map(|fd| unsafe { HttpListener::from_raw_fd(fd) })
+}
+
+/// Notifications are ignored when not running as a systemd service
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Pings are sent from a timer thread at half the `WatchdogSec=` interval, as
+/// recommended, but only while the network command loop is waiting for
+/// commands or made progress within the interval. A stuck loop thus makes
+/// systemd restart WiFi Connect, while long waits inside it do not.
+pub fn start_watchdog() {
+    let mut usec = 0;
+
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+
+    let timeout = Duration::from_micros(usec);
+    let interval = timeout / 2;
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let alive = match *BUSY_SINCE.lock().unwrap() {
+            Some(since) => since.elapsed() < timeout,
+            None => true,
+        };
+
+        if alive {
+            notify(&[NotifyState::Watchdog]);
+        } else {
+            warn!("Network command loop is not making progress. Skipping watchdog ping");
+        }
+    });
+}
+
+/// Called by the network command loop before waiting for the next command
+pub fn watchdog_idle() {
+    *BUSY_SINCE.lock().unwrap() = None;
+}
+
+/// Called by the network command loop when it receives a command, and inside
+/// long waits to tell that it is still making progress
+pub fn watchdog_progress() {
+    *BUSY_SINCE.lock().unwrap() = Some(Instant::now());
+}
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        debug!("Notifying systemd failed: {}", e);
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslStream};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+
+use config::TlsConfig;
+use errors::*;
+
+const CERTIFICATE_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const CERTIFICATE_VALIDITY_DAYS: u32 = 3650;
+
+/// The handshake runs on a server worker thread, so a client that connects
+/// and sends nothing must not hold it for long. Iron's own timeouts apply
+/// once the handshake is done.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// HTTPS support for the Iron server backed by OpenSSL
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: Arc<SslAcceptor>,
+}
+
+impl TlsServer {
+    pub fn new(config: &TlsConfig, gateway: &Ipv4Addr) -> Result<Self> {
+        let (certificate, key) = load_or_generate_key_pair(config, gateway)?;
+
+        let acceptor = create_acceptor(&certificate, &key).chain_err(|| ErrorKind::TlsAcceptor)?;
+
+        Ok(TlsServer {
+            acceptor: Arc::new(acceptor),
+        })
+    }
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let timeout = Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS));
+        stream.0.set_read_timeout(timeout)?;
+        stream.0.set_write_timeout(timeout)?;
+
+        match self.acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(e) => Err(hyper::Error::Ssl(Box::new(e))),
+        }
+    }
+}
+
+/// The stream is shared between the request reader and the response writer
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<SslStream<HttpStream>>>);
+
+impl TlsStream {
+    fn lock(&self) -> MutexGuard<'_, SslStream<HttpStream>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.lock().get_mut().close(how)
+    }
+}
+
+fn create_acceptor(certificate: &X509, key: &PKey<Private>) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate(certificate)?;
+    builder.set_private_key(key)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+/// Uses the user-provided certificate and key if specified, otherwise a
+/// self-signed certificate is generated once and reused on subsequent runs
+fn load_or_generate_key_pair(
+    config: &TlsConfig,
+    gateway: &Ipv4Addr,
+) -> Result<(X509, PKey<Private>)> {
+    match (&config.certificate, &config.key) {
+        (Some(certificate), Some(key)) => {
+            info!("Using TLS certificate {}", certificate.display());
+            load_key_pair(certificate, key)
+        }
+        (None, None) => {
+            let certificate = config.directory.join(CERTIFICATE_FILE);
+            let key = config.directory.join(KEY_FILE);
+
+            if certificate.exists() && key.exists() {
+                info!(
+                    "Using self-signed TLS certificate {}",
+                    certificate.display()
+                );
+                load_key_pair(&certificate, &key)
+            } else {
+                info!(
+                    "Generating self-signed TLS certificate {}",
+                    certificate.display()
+                );
+                generate_key_pair(&certificate, &key, gateway)
+                    .chain_err(|| ErrorKind::TlsGenerateCertificate)
+            }
+        }
+        _ => bail!(ErrorKind::TlsIncompleteKeyPair),
+    }
+}
+
+fn load_key_pair(certificate: &Path, key: &Path) -> Result<(X509, PKey<Private>)> {
+    load_key_pair_impl(certificate, key)
+        .chain_err(|| ErrorKind::TlsLoadCertificate(certificate.display().to_string()))
+}
+
+fn load_key_pair_impl(certificate: &Path, key: &Path) -> Result<(X509, PKey<Private>)> {
+    let certificate = X509::from_pem(&read_file(certificate)?)?;
+    let key = PKey::private_key_from_pem(&read_file(key)?)?;
+    Ok((certificate, key))
+}
+
+fn generate_key_pair(
+    certificate_path: &Path,
+    key_path: &Path,
+    gateway: &Ipv4Addr,
+) -> Result<(X509, PKey<Private>)> {
+    let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", &gateway.to_string())?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERTIFICATE_VALIDITY_DAYS)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(BasicConstraints::new().critical().build()?)?;
+
+    let subject_alt_name = SubjectAlternativeName::new()
+        .ip(&gateway.to_string())
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(subject_alt_name)?;
+
+    builder.sign(&key, MessageDigest::sha256())?;
+    let certificate = builder.build();
+
+    fs::create_dir_all(certificate_path.parent().unwrap_or_else(|| Path::new(".")))?;
+
+    write_file(key_path, &key.private_key_to_pem_pkcs8()?, 0o600)?;
+    write_file(certificate_path, &certificate.to_pem()?, 0o644)?;
+
+    Ok((certificate, key))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn write_file(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    file.write_all(contents)?;
+    Ok(())
+}
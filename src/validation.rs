@@ -0,0 +1,231 @@
+/// Credentials are validated before the captive portal is torn down, so that
+/// obvious typos do not cost the user a reconnection to the portal.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(code: &'static str, message: &str) -> Self {
+        ValidationError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+const MAX_SSID_BYTES: usize = 32;
+
+pub fn validate_ssid(ssid: &str) -> Result<(), ValidationError> {
+    if ssid.is_empty() {
+        return Err(ValidationError::new("invalid_ssid", "SSID cannot be empty"));
+    }
+
+    if ssid.len() > MAX_SSID_BYTES {
+        return Err(ValidationError::new(
+            "invalid_ssid",
+            "SSID cannot be longer than 32 bytes",
+        ));
+    }
+
+    Ok(())
+}
+
+/// `security` is the security type as reported by `/networks`
+pub fn validate_credentials(
+    security: &str,
+    identity: &str,
+    passphrase: &str,
+) -> Result<(), ValidationError> {
+    match security {
+        "enterprise" => validate_enterprise(identity, passphrase),
+        "wpa" => validate_wpa_passphrase(passphrase),
+        "wep" => validate_wep_key(passphrase),
+        _ => Ok(()),
+    }
+}
+
+fn validate_enterprise(identity: &str, passphrase: &str) -> Result<(), ValidationError> {
+    if identity.is_empty() {
+        return Err(ValidationError::new(
+            "identity_required",
+            "An identity is required for enterprise networks",
+        ));
+    }
+
+    if passphrase.is_empty() {
+        return Err(ValidationError::new(
+            "invalid_passphrase",
+            "A passphrase is required for enterprise networks",
+        ));
+    }
+
+    Ok(())
+}
+
+/// WPA accepts an 8 to 63 characters ASCII passphrase or a 64 hex digits key
+fn validate_wpa_passphrase(passphrase: &str) -> Result<(), ValidationError> {
+    if passphrase.len() == 64 && is_hex(passphrase) {
+        return Ok(());
+    }
+
+    if !is_printable_ascii(passphrase) || passphrase.len() < 8 || passphrase.len() > 63 {
+        return Err(ValidationError::new(
+            "invalid_passphrase",
+            "WPA passphrase should be 8 to 63 ASCII characters or 64 hexadecimal digits",
+        ));
+    }
+
+    Ok(())
+}
+
+/// WEP accepts 5 or 13 ASCII characters or 10 or 26 hex digits keys
+fn validate_wep_key(key: &str) -> Result<(), ValidationError> {
+    let valid = match key.len() {
+        5 | 13 => is_printable_ascii(key),
+        10 | 26 => is_hex(key),
+        _ => false,
+    };
+
+    if !valid {
+        return Err(ValidationError::new(
+            "invalid_passphrase",
+            "WEP key should be 5 or 13 ASCII characters or 10 or 26 hexadecimal digits",
+        ));
+    }
+
+    Ok(())
+}
+
+fn is_hex(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_printable_ascii(value: &str) -> bool {
+    value.chars().all(|c| (' '..='~').contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(result: Result<(), ValidationError>) -> Option<&'static str> {
+        result.err().map(|e| e.code)
+    }
+
+    #[test]
+    fn ssid_length() {
+        assert_eq!(error_code(validate_ssid("")), Some("invalid_ssid"));
+        assert_eq!(error_code(validate_ssid("home")), None);
+        assert_eq!(error_code(validate_ssid(&"a".repeat(32))), None);
+        assert_eq!(
+            error_code(validate_ssid(&"a".repeat(33))),
+            Some("invalid_ssid")
+        );
+    }
+
+    #[test]
+    fn ssid_length_is_counted_in_bytes() {
+        // 11 characters of 3 bytes each
+        assert_eq!(
+            error_code(validate_ssid(&"\u{20ac}".repeat(11))),
+            Some("invalid_ssid")
+        );
+    }
+
+    #[test]
+    fn wpa_passphrase() {
+        assert_eq!(
+            error_code(validate_credentials("wpa", "", "password")),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials("wpa", "", &"a".repeat(63))),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials("wpa", "", "short")),
+            Some("invalid_passphrase")
+        );
+        assert_eq!(
+            error_code(validate_credentials("wpa", "", &"z".repeat(64))),
+            Some("invalid_passphrase")
+        );
+        assert_eq!(
+            error_code(validate_credentials("wpa", "", "pass\u{e9}word")),
+            Some("invalid_passphrase")
+        );
+    }
+
+    #[test]
+    fn wpa_hex_key() {
+        assert_eq!(
+            error_code(validate_credentials(
+                "wpa",
+                "",
+                &"0123456789abcdef".repeat(4)
+            )),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials(
+                "wpa",
+                "",
+                &"0123456789abcdeg".repeat(4)
+            )),
+            Some("invalid_passphrase")
+        );
+    }
+
+    #[test]
+    fn wep_key() {
+        assert_eq!(error_code(validate_credentials("wep", "", "abcde")), None);
+        assert_eq!(
+            error_code(validate_credentials("wep", "", "abcdefghijklm")),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials("wep", "", "0123456789")),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials(
+                "wep",
+                "",
+                "0123456789abcdef0123456789"
+            )),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials("wep", "", "abcdefghij")),
+            Some("invalid_passphrase")
+        );
+        assert_eq!(
+            error_code(validate_credentials("wep", "", "abcdef")),
+            Some("invalid_passphrase")
+        );
+    }
+
+    #[test]
+    fn enterprise_credentials() {
+        assert_eq!(
+            error_code(validate_credentials("enterprise", "user", "secret")),
+            None
+        );
+        assert_eq!(
+            error_code(validate_credentials("enterprise", "", "secret")),
+            Some("identity_required")
+        );
+        assert_eq!(
+            error_code(validate_credentials("enterprise", "user", "")),
+            Some("invalid_passphrase")
+        );
+    }
+
+    #[test]
+    fn open_networks_accept_anything() {
+        assert_eq!(error_code(validate_credentials("none", "", "")), None);
+        assert_eq!(error_code(validate_credentials("unknown", "", "x")), None);
+    }
+}
@@ -31,6 +31,48 @@ export interface Network {
 	security: string;
 }
 
+const responseError = (resp: Response): Promise<never> =>
+	resp
+		.json()
+		.catch(() => ({}))
+		.then((body) => {
+			throw new Error(
+				(body && body.error && body.error.message) || resp.statusText,
+			);
+		});
+
+const login = (): Promise<void> => {
+	const code = window.prompt('Enter the access code of the device');
+
+	if (code === null) {
+		return Promise.reject(new Error('Access code required'));
+	}
+
+	return fetch('/api/v1/login', {
+		method: 'POST',
+		body: JSON.stringify({ code }),
+		headers: {
+			'Content-Type': 'application/json',
+		},
+	}).then((resp) => {
+		if (resp.status !== 200) {
+			return responseError(resp);
+		}
+	});
+};
+
+const fetchWithAccessCode = (
+	input: string,
+	init?: RequestInit,
+): Promise<Response> =>
+	fetch(input, init).then((resp) => {
+		if (resp.status !== 401) {
+			return resp;
+		}
+
+		return login().then(() => fetchWithAccessCode(input, init));
+	});
+
 const App = () => {
 	const [attemptedConnect, setAttemptedConnect] = React.useState(false);
 	const [isFetchingNetworks, setIsFetchingNetworks] = React.useState(true);
@@ -40,10 +82,10 @@ const App = () => {
 	);
 
 	React.useEffect(() => {
-		fetch('/networks')
+		fetchWithAccessCode('/api/v1/networks')
 			.then((data) => {
 				if (data.status !== 200) {
-					throw new Error(data.statusText);
+					return responseError(data);
 				}
 
 				return data.json();
@@ -61,7 +103,7 @@ const App = () => {
 		setAttemptedConnect(true);
 		setError('');
 
-		fetch('/connect', {
+		fetchWithAccessCode('/api/v1/connection', {
 			method: 'POST',
 			body: JSON.stringify(data),
 			headers: {
@@ -69,8 +111,8 @@ const App = () => {
 			},
 		})
 			.then((resp) => {
-				if (resp.status !== 200) {
-					throw new Error(resp.statusText);
+				if (resp.status !== 202) {
+					return responseError(resp);
 				}
 			})
 			.catch((e: Error) => {